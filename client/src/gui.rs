@@ -46,6 +46,7 @@ enum LoginElements {
     LoginUsernameBox,
     LoginPasswordBox,
     LoginConnectButton,
+    LoginRegisterButton,
     LoginElementsCount,
 }
 
@@ -241,6 +242,12 @@ const LOGIN_LAYOUT_RECTANGLES: [Rectangle; LoginElements::LoginElementsCount as
         MENU_ELEMENT_FULLWIDTH,
         MENU_ELEMENT_HEIGHT,
     ),
+    Rectangle::new(
+        MENU_GAP,
+        3.0 * MENU_ELEMENT_HEIGHT + 4.0 * MENU_GAP,
+        MENU_ELEMENT_FULLWIDTH,
+        MENU_ELEMENT_HEIGHT,
+    ),
 ];
 
 const MAIN_HUD_LAYOUT_RECTANGLES: [Rectangle; MainHudElements::MainHudElementsCount as usize] = [
@@ -409,6 +416,8 @@ pub struct LoginScreenState {
     pub port_input_active: bool,
     pub connect_button_title: String,
     pub connect_button_active: bool,
+    pub register_button_title: String,
+    pub register_button_active: bool,
 }
 
 fn make_login_screen_state() -> LoginScreenState {
@@ -427,6 +436,8 @@ fn make_login_screen_state() -> LoginScreenState {
         port_input_active: false,
         connect_button_title: "DESCENDER AL AVERNO".to_string(),
         connect_button_active: false,
+        register_button_title: "CREAR CUENTA".to_string(),
+        register_button_active: false,
     }
 }
 
@@ -851,6 +862,14 @@ impl ClientUi {
         false
     }
 
+    pub fn should_attempt_register(&mut self) -> bool {
+        if self.current_login.register_button_active {
+            self.current_login.register_button_active = false;
+            return true;
+        }
+        false
+    }
+
     /// Port of ClientUi::toggleSettingsMenu()
     pub fn toggle_settings_menu(&mut self) {
         self.ui_state.settings = !self.ui_state.settings;
@@ -1318,6 +1337,14 @@ impl ClientUi {
         if d.gui_button(connect_rect, &self.current_login.connect_button_title) {
             self.current_login.connect_button_active = true;
         }
+
+        let register_rect = ClientUi::move_rectangle_to(
+            LOGIN_LAYOUT_RECTANGLES[LoginElements::LoginRegisterButton as usize],
+            self.current_login.anchor,
+        );
+        if d.gui_button(register_rect, &self.current_login.register_button_title) {
+            self.current_login.register_button_active = true;
+        }
     }
 
     // --- Empty Stubs (ported) ---
@@ -17,7 +17,7 @@ use raylib::prelude::GuiTextAlignmentVertical::*;
 use raylib::prelude::GuiTextWrapMode::*;
 use raylib::prelude::KeyboardKey::*;
 use raylib::prelude::*;
-use common::MessageTypeClientToServer;
+use common::{AuthFailReason, MessageTypeClientToServer, MessageTypeServerToClient, RegisterFailReason};
 use gui::ClientUi;
 use crate::network::ClientNetwork;
 
@@ -38,12 +38,47 @@ fn main() {
     ui.ui_state.stats_bar = true;
     ui.ui_state.fps_ping = true;
 
-    let to_send = MessageTypeClientToServer::Auth {token: "ASDASD".to_string()};
-
-    net.queue_send(to_send);
     while !rl.window_should_close() {
         ui.update(&rl);
 
+        if ui.should_attempt_login() {
+            let login = ui.get_login_data();
+            let to_send = MessageTypeClientToServer::Auth {
+                username: login.username_input_text.trim().to_string(),
+                password: login.password_input_text.clone(),
+            };
+            net.queue_send(to_send);
+            ui.set_login_feedback_message("CONECTANDO...".to_string());
+        }
+
+        if ui.should_attempt_register() {
+            let login = ui.get_login_data();
+            let to_send = MessageTypeClientToServer::Register {
+                username: login.username_input_text.trim().to_string(),
+                password: login.password_input_text.clone(),
+            };
+            net.queue_send(to_send);
+        }
+
+        for msg in net.poll_inbound() {
+            match msg {
+                MessageTypeServerToClient::AuthOk { .. } => {
+                    ui.ui_state.login_screen = false;
+                }
+                MessageTypeServerToClient::AuthFailed { reason } => {
+                    ui.set_login_feedback_message("DESCENDER AL AVERNO".to_string());
+                    ui.activate_modal_popup(auth_fail_message(&reason));
+                }
+                MessageTypeServerToClient::RegisterOk => {
+                    ui.activate_modal_popup("Cuenta creada, ya puede iniciar sesión.".to_string());
+                }
+                MessageTypeServerToClient::RegisterFailed { reason } => {
+                    ui.activate_modal_popup(register_fail_message(&reason));
+                }
+                _ => {}
+            }
+        }
+
         let mut d = rl.begin_drawing(&thread);
 
         d.clear_background(Color::BLACK);
@@ -56,6 +91,34 @@ fn main() {
 
 }
 
+fn auth_fail_message(reason: &AuthFailReason) -> String {
+    match reason {
+        AuthFailReason::InvalidCredentials => "Usuario o contraseña incorrectos.".to_string(),
+        AuthFailReason::DbError => "Error del servidor, intente nuevamente.".to_string(),
+        AuthFailReason::Banned => "La cuenta está suspendida.".to_string(),
+        AuthFailReason::ServerFull => "El servidor está lleno.".to_string(),
+        AuthFailReason::SessionExpired => "La sesión expiró, inicie sesión nuevamente.".to_string(),
+        AuthFailReason::AlreadyLoggedIn => "La cuenta ya está conectada.".to_string(),
+        AuthFailReason::Throttled { retry_after_secs } => {
+            format!("Demasiados intentos, espere {} segundos.", retry_after_secs)
+        }
+        AuthFailReason::LockedOut { retry_after_secs } => {
+            format!("Cuenta bloqueada temporalmente, intente en {} minutos.", retry_after_secs.div_ceil(60))
+        }
+    }
+}
+
+fn register_fail_message(reason: &RegisterFailReason) -> String {
+    match reason {
+        RegisterFailReason::InvalidUsername => "Nombre inválido: 3 a 20 letras, números o guiones bajos.".to_string(),
+        RegisterFailReason::WeakPassword => "La contraseña debe tener al menos 8 caracteres.".to_string(),
+        RegisterFailReason::NameTaken => "Ese nombre de usuario ya existe.".to_string(),
+        RegisterFailReason::ServerError => "Error del servidor, intente nuevamente.".to_string(),
+        RegisterFailReason::Throttled { retry_after_secs } => {
+            format!("Demasiados intentos, espere {} segundos.", retry_after_secs)
+        }
+    }
+}
 
 /*
 //------------------------------------------------------------------------------------
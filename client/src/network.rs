@@ -7,19 +7,27 @@ use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use bincode;
-use common::{ConnectionState, MessageTypeClientToServer, MessageTypeServerToClient};
+use common::{AuthFailReason, ConnectionState, MessageTypeClientToServer, MessageTypeServerToClient};
 use gns::sys::*;
 use gns::*;
 use log::*;
 use serde::{Deserialize, Serialize};
 use tokio::runtime::Runtime;
 
+// Wait before the second reconnect attempt, doubled for every further one
+const RECONNECT_DELAY_MS: u64 = 1000;
+const MAX_RECONNECT_DELAY_MS: u64 = 30_000;
+// Attempts in a row before the session is given up and the player has to log in again
+const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+
 pub struct ClientNetwork {
     // state
     should_shutdown: Arc<Mutex<bool>>,
     inbound: Arc<Mutex<VecDeque<MessageTypeServerToClient>>>,
     outbound: Arc<Mutex<VecDeque<MessageTypeClientToServer>>>,
     connection_state: Arc<Mutex<ConnectionState>>,
+    // last token from AuthOk, used to Resume after a dropped connection
+    session_token: Arc<Mutex<Option<String>>>,
 }
 
 impl ClientNetwork {
@@ -29,6 +37,7 @@ impl ClientNetwork {
             inbound: Arc::new(Mutex::new(VecDeque::new())),
             outbound: Arc::new(Mutex::new(VecDeque::new())),
             connection_state: Arc::new(Mutex::new(ConnectionState::NetworkUninitialized)),
+            session_token: Arc::new(Mutex::new(None)),
         }
     }
 
@@ -37,10 +46,13 @@ impl ClientNetwork {
         let inbound = Arc::clone(&self.inbound);
         let outbound = Arc::clone(&self.outbound);
         let connection_state = Arc::clone(&self.connection_state);
+        let session_token = Arc::clone(&self.session_token);
 
         thread::spawn(move || {
             info!("client: network thread starting -> {}", server_addr);
             let mut quit = false;
+            let mut reconnect_attempts: u32 = 0;
+            let mut next_reconnect: Option<Instant> = None;
 
             // Initialize gns global and enable debugging
             let gns_global = gns::GnsGlobal::get().expect("gns init");
@@ -49,7 +61,7 @@ impl ClientNetwork {
                 |ty, message| println!("{:#?}: {}", ty, message),
             );
             // Create client socket
-            let client_socket = gns::GnsSocket::new(gns_global.clone()).connect(server_addr, 3750).unwrap();
+            let mut client_socket = gns::GnsSocket::new(gns_global.clone()).connect(server_addr, 3750).unwrap();
 
             'net_loop: loop {
                 gns_global.poll_callbacks();
@@ -68,7 +80,7 @@ impl ClientNetwork {
                         ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_Connected,
                     ) => {
                         println!("GnsSocket<Client>: connected to server.");
-                        *connection_state.lock().unwrap() = ConnectionState::Connected
+                        *connection_state.lock().unwrap() = ConnectionState::Connected;
 
                     }
                     (_, ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ClosedByPeer | ESteamNetworkingConnectionState::k_ESteamNetworkingConnectionState_ProblemDetectedLocally) => {
@@ -86,8 +98,25 @@ impl ClientNetwork {
                     if let Ok(smsg) = bincode::deserialize::<MessageTypeServerToClient>(message.payload())
                     {
                         match smsg {
-                            MessageTypeServerToClient::AuthOk => {
+                            MessageTypeServerToClient::AuthOk { session_token: ref token } => {
                                 println!("GnsSocket<Client>: auth ok form server!");
+                                *session_token.lock().unwrap() = Some(token.clone());
+                                // Only a session that is back counts, a connection the server drops right away does not
+                                reconnect_attempts = 0;
+                                next_reconnect = None;
+                            }
+                            MessageTypeServerToClient::AuthFailed { reason: AuthFailReason::SessionExpired } => {
+                                // Resume didn't work, the player has to log in again
+                                *session_token.lock().unwrap() = None;
+                            }
+                            MessageTypeServerToClient::AuthFailed { ref reason } => {
+                                println!("GnsSocket<Client>: auth failed: {:?}", reason);
+                            }
+                            MessageTypeServerToClient::RegisterOk => {
+                                println!("GnsSocket<Client>: account created!");
+                            }
+                            MessageTypeServerToClient::RegisterFailed { ref reason } => {
+                                println!("GnsSocket<Client>: register failed: {:?}", reason);
                             }
                             MessageTypeServerToClient::GameState {..} => {
 
@@ -100,6 +129,38 @@ impl ClientNetwork {
                     }
                 });
 
+                // Lost the server, reconnect and pick the session back up if we had one.
+                // A connect can also fail later through ProblemDetectedLocally, so every
+                // attempt counts and waits its turn, not just the ones that error right away.
+                let reconnect_due = next_reconnect.is_none_or(|at| Instant::now() >= at);
+                let wants_reconnect = quit && reconnect_due && session_token.lock().unwrap().is_some();
+                if wants_reconnect && reconnect_attempts >= MAX_RECONNECT_ATTEMPTS {
+                    warn!("client: giving up after {} reconnect attempts", reconnect_attempts);
+                    *session_token.lock().unwrap() = None;
+                    *connection_state.lock().unwrap() = ConnectionState::NetworkUninitialized;
+                    reconnect_attempts = 0;
+                    next_reconnect = None;
+                } else if wants_reconnect {
+                    quit = false;
+                    reconnect_attempts += 1;
+                    let delay = RECONNECT_DELAY_MS.saturating_mul(1 << (reconnect_attempts - 1).min(16));
+                    next_reconnect = Some(Instant::now() + Duration::from_millis(delay.min(MAX_RECONNECT_DELAY_MS)));
+                    *connection_state.lock().unwrap() = ConnectionState::Connecting;
+                    client_socket.close_connection(client_socket.connection(), 0, "", false);
+                    match gns::GnsSocket::new(gns_global.clone()).connect(server_addr, 3750) {
+                        Ok(socket) => {
+                            client_socket = socket;
+                            if let Some(token) = session_token.lock().unwrap().clone() {
+                                outbound.lock().unwrap().push_front(MessageTypeClientToServer::Resume { token });
+                            }
+                        }
+                        Err(e) => {
+                            warn!("client: reconnect {} failed: {:?}", reconnect_attempts, e);
+                            quit = true;
+                        }
+                    }
+                }
+
                 let mut q = outbound.lock().unwrap();
 
                 if *connection_state.lock().unwrap() == ConnectionState::Connected{
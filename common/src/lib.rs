@@ -0,0 +1,75 @@
+use serde::{Deserialize, Serialize};
+
+/// Where the client's connection to the server stands.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConnectionState {
+    NetworkUninitialized,
+    Connecting,
+    Connected,
+}
+
+/// Why the server refused an Auth or Resume.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AuthFailReason {
+    /// Unknown username or wrong password, deliberately not told apart.
+    InvalidCredentials,
+    Banned,
+    ServerFull,
+    /// The server could not reach its database, try again later.
+    DbError,
+    /// Too many failed logins, the next try is refused until this many seconds pass.
+    Throttled { retry_after_secs: u64 },
+    /// Too many failed logins in a row, the account or address is blocked for a while.
+    LockedOut { retry_after_secs: u64 },
+    /// The session token of a Resume is unknown, expired or past its reconnect window.
+    SessionExpired,
+    /// The account is already in use and the server keeps the existing session.
+    AlreadyLoggedIn,
+}
+
+/// Why the server refused a Register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RegisterFailReason {
+    /// 3 to 20 ASCII letters, digits or underscores.
+    InvalidUsername,
+    /// Shorter than the server's minimum length.
+    WeakPassword,
+    NameTaken,
+    ServerError,
+    /// Too many registrations from this address, try again after this many seconds.
+    Throttled { retry_after_secs: u64 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MessageTypeClientToServer {
+    Auth { username: String, password: String },
+    /// Creates an account, the client still has to Auth with it afterwards.
+    Register { username: String, password: String },
+    /// Picks up the session of a dropped connection with the token from AuthOk.
+    Resume { token: String },
+    Ping { client_time_ms: u64 },
+    PlayerMove { x: f32, y: f32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum MessageTypeServerToClient {
+    /// `session_token` lets the client Resume after a dropped connection.
+    AuthOk { session_token: String },
+    AuthFailed { reason: AuthFailReason },
+    RegisterOk,
+    RegisterFailed { reason: RegisterFailReason },
+    GameState { tick: u64 },
+    Pong { client_time_ms: u64 },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_survive_bincode() {
+        let msg = MessageTypeClientToServer::Auth { username: "bob".into(), password: "secret".into() };
+        let bytes = bincode::serialize(&msg).unwrap();
+        assert_eq!(bincode::deserialize::<MessageTypeClientToServer>(&bytes).unwrap(), msg);
+    }
+}
@@ -0,0 +1,11 @@
+-- Accounts. Servers whose table was created by hand before there were migrations adopt it:
+-- the table is only created when missing, every later column is added on its own.
+CREATE TABLE IF NOT EXISTS "USER" (
+    id SERIAL PRIMARY KEY,
+    userName VARCHAR(20) NOT NULL,
+    passwordHash TEXT NOT NULL
+);
+-- Named like the constraint UNIQUE would get, so a table that already has one is left alone
+CREATE UNIQUE INDEX IF NOT EXISTS "USER_username_key" ON "USER" (userName);
+ALTER TABLE "USER" ADD COLUMN IF NOT EXISTS banned BOOLEAN NOT NULL DEFAULT FALSE;
+ALTER TABLE "USER" ADD COLUMN IF NOT EXISTS createdAt TIMESTAMPTZ NOT NULL DEFAULT now();
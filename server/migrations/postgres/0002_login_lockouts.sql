@@ -0,0 +1,5 @@
+-- Lockouts started by the login throttle, keyed "account:<name>" or "addr:<ip>".
+CREATE TABLE "LOGIN_LOCKOUT" (
+    lockKey TEXT PRIMARY KEY,
+    lockedUntil TIMESTAMPTZ NOT NULL
+);
@@ -0,0 +1,12 @@
+CREATE TABLE "CHARACTER" (
+    id SERIAL PRIMARY KEY,
+    userId INTEGER NOT NULL REFERENCES "USER" (id) ON DELETE CASCADE,
+    name VARCHAR(20) NOT NULL UNIQUE,
+    level INTEGER NOT NULL DEFAULT 1,
+    posX REAL NOT NULL DEFAULT 0,
+    posY REAL NOT NULL DEFAULT 0,
+    createdAt TIMESTAMPTZ NOT NULL DEFAULT now(),
+    lastPlayedAt TIMESTAMPTZ
+);
+
+CREATE INDEX "CHARACTER_userId_idx" ON "CHARACTER" (userId);
@@ -0,0 +1,13 @@
+-- Ban history. "USER".banned stays the flag checked at login.
+CREATE TABLE "BAN" (
+    id SERIAL PRIMARY KEY,
+    userId INTEGER NOT NULL REFERENCES "USER" (id) ON DELETE CASCADE,
+    reason TEXT NOT NULL,
+    bannedBy TEXT NOT NULL,
+    createdAt TIMESTAMPTZ NOT NULL DEFAULT now(),
+    -- NULL means permanent
+    expiresAt TIMESTAMPTZ,
+    liftedAt TIMESTAMPTZ
+);
+
+CREATE INDEX "BAN_userId_idx" ON "BAN" (userId);
@@ -0,0 +1,7 @@
+CREATE TABLE "USER" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    userName TEXT NOT NULL UNIQUE,
+    passwordHash TEXT NOT NULL,
+    banned INTEGER NOT NULL DEFAULT 0,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,5 @@
+-- lockedUntil is unix seconds
+CREATE TABLE "LOGIN_LOCKOUT" (
+    lockKey TEXT PRIMARY KEY,
+    lockedUntil INTEGER NOT NULL
+);
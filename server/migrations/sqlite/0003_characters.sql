@@ -0,0 +1,12 @@
+CREATE TABLE "CHARACTER" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    userId INTEGER NOT NULL REFERENCES "USER" (id) ON DELETE CASCADE,
+    name TEXT NOT NULL UNIQUE,
+    level INTEGER NOT NULL DEFAULT 1,
+    posX REAL NOT NULL DEFAULT 0,
+    posY REAL NOT NULL DEFAULT 0,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    lastPlayedAt TEXT
+);
+
+CREATE INDEX "CHARACTER_userId_idx" ON "CHARACTER" (userId);
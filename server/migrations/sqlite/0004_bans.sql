@@ -0,0 +1,11 @@
+CREATE TABLE "BAN" (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    userId INTEGER NOT NULL REFERENCES "USER" (id) ON DELETE CASCADE,
+    reason TEXT NOT NULL,
+    bannedBy TEXT NOT NULL,
+    createdAt TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expiresAt TEXT,
+    liftedAt TEXT
+);
+
+CREATE INDEX "BAN_userId_idx" ON "BAN" (userId);
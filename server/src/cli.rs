@@ -0,0 +1,207 @@
+use std::io::{BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use anyhow::{anyhow, bail, Context};
+use clap::{Parser, Subcommand};
+
+use crate::config::ServerConfig;
+use crate::db::{DbStmt, DbWorker};
+use crate::rows::{FromDbRow, NewUserRow, UserRow};
+use crate::network::{is_valid_username, ServerNetwork, PASSWORD_MIN_LEN};
+
+#[derive(Parser, Debug)]
+#[command(name = "server", about = "Averno game server")]
+pub struct Cli {
+    /// Config file, defaults to $AVERNO_CONFIG or ./server.toml
+    #[arg(short, long, global = true)]
+    pub config: Option<PathBuf>,
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the game server (the default)
+    Serve,
+    /// Create an account, the password is read from stdin
+    CreateUser { username: String },
+    /// Set a new password for an account, read from stdin
+    ResetPassword { username: String },
+    /// Hash a password read from stdin and print the PHC string
+    HashPassword,
+    /// Apply pending database migrations
+    Migrate {
+        /// Only report applied, pending and drifted migrations, and check the
+        /// statements against an up to date schema
+        #[arg(long)]
+        check: bool,
+    },
+    /// Validate the config and print the effective values
+    CheckConfig,
+}
+
+impl Cli {
+    pub fn run(self) -> anyhow::Result<()> {
+        let path = self.config.or_else(ServerConfig::default_path);
+        let path = path.as_deref();
+        match self.command.unwrap_or(Command::Serve) {
+            Command::Serve => serve(ServerConfig::load(path)?),
+            Command::CreateUser { username } => create_user(ServerConfig::load(path)?, &username),
+            Command::ResetPassword { username } => reset_password(ServerConfig::load(path)?, &username),
+            // Only needs the Argon2 section, works without a database configured
+            Command::HashPassword => hash_password(ServerConfig::read(path)?),
+            Command::Migrate { check } => migrate(ServerConfig::load(path)?, check),
+            Command::CheckConfig => check_config(ServerConfig::load(path)?),
+        }
+    }
+}
+
+fn serve(config: ServerConfig) -> anyhow::Result<()> {
+    let net = ServerNetwork::new(config)?;
+    let shutdown = net.shutdown_handle();
+    if let Err(e) = ctrlc::set_handler(move || {
+        println!("--- Signal received, shutting down ---");
+        shutdown.shutdown();
+    }) {
+        eprintln!("could not install signal handler: {}", e);
+    }
+
+    net.start()?
+        .join()
+        .map_err(|_| anyhow!("network thread panicked"))?;
+    println!("--- Runtime has shut down. Program exiting. ---");
+    Ok(())
+}
+
+/// Reads a password from stdin, prompting when it is a terminal. Input is not hidden,
+/// pipe it in (`echo pw | server create-user bob`) to keep it off the screen.
+fn read_password(confirm: bool) -> anyhow::Result<String> {
+    let stdin = std::io::stdin();
+    let interactive = stdin.is_terminal();
+    let read = |prompt: &str| -> anyhow::Result<String> {
+        if interactive {
+            eprint!("{}", prompt);
+            std::io::stderr().flush().ok();
+        }
+        let mut line = String::new();
+        stdin.lock().read_line(&mut line).context("could not read password")?;
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    };
+
+    let password = read("Password: ")?;
+    if confirm && interactive && read("Repeat password: ")? != password {
+        bail!("passwords do not match");
+    }
+    if password.chars().count() < PASSWORD_MIN_LEN {
+        bail!("password must be at least {} characters", PASSWORD_MIN_LEN);
+    }
+    Ok(password)
+}
+
+fn open_db(config: &ServerConfig) -> anyhow::Result<DbWorker> {
+    DbWorker::new(&config.database.url, config.db_worker_config())
+        .map_err(|e| anyhow!("could not connect to the database: {}", e))
+}
+
+fn close_db(db: DbWorker, config: &ServerConfig) {
+    if !db.shutdown(config.shutdown_timeout()) {
+        eprintln!("DB workers did not stop in time");
+    }
+}
+
+fn hash_with(config: &ServerConfig, password: &str) -> anyhow::Result<String> {
+    config.hasher_policy()?.make_hash(password).map_err(|e| anyhow!("hashing failed: {}", e))
+}
+
+fn create_user(config: ServerConfig, username: &str) -> anyhow::Result<()> {
+    if !is_valid_username(username) {
+        bail!("invalid username {:?}, use 3 to 20 letters, digits or underscores", username);
+    }
+    let hash = hash_with(&config, &read_password(true)?)?;
+
+    let db = open_db(&config)?;
+    let result = db.query_sync(DbStmt::CreateUser, vec![username.to_string(), hash]);
+    close_db(db, &config);
+
+    let rows = result.map_err(|e| anyhow!("could not create user: {}", e))?;
+    match rows.first() {
+        Some(row) => {
+            let user = NewUserRow::from_row(row).map_err(|e| anyhow!("unexpected CreateUser row: {}", e))?;
+            println!("created user {:?} with id {}", username, user.id);
+            Ok(())
+        }
+        None => bail!("username {:?} is already taken", username),
+    }
+}
+
+fn reset_password(config: ServerConfig, username: &str) -> anyhow::Result<()> {
+    let db = open_db(&config)?;
+    let result: anyhow::Result<()> = (|| {
+        let rows = db
+            .query_sync(DbStmt::GetUser, vec![username.to_string()])
+            .map_err(|e| anyhow!("could not look up user: {}", e))?;
+        let row = rows.first().ok_or_else(|| anyhow!("no user named {:?}", username))?;
+        let user = UserRow::from_row(row).map_err(|e| anyhow!("unexpected GetUser row: {}", e))?;
+        let hash = hash_with(&config, &read_password(true)?)?;
+        db.query_sync(DbStmt::UpdatePasswordHash, vec![user.id.to_string(), hash])
+            .map_err(|e| anyhow!("could not update password: {}", e))?;
+        Ok(())
+    })();
+    close_db(db, &config);
+
+    result?;
+    // Existing session tokens live in the running server and expire on their own
+    println!("password of {:?} updated", username);
+    Ok(())
+}
+
+fn hash_password(config: ServerConfig) -> anyhow::Result<()> {
+    let policy = config.hasher_policy()?;
+    policy.validate()?;
+    let hash = policy.make_hash(&read_password(false)?)
+        .map_err(|e| anyhow!("hashing failed: {}", e))?;
+    println!("{}", hash);
+    Ok(())
+}
+
+fn migrate(config: ServerConfig, check: bool) -> anyhow::Result<()> {
+    let db = open_db(&config)?;
+    let result = if check {
+        db.migration_status().map(|status| {
+            println!("applied: {:?}", status.applied);
+            println!("pending: {:?}", status.pending);
+            for drift in &status.drift {
+                println!("drift: {}", drift);
+            }
+            status.is_current()
+        }).and_then(|current| {
+            // Statements are only expected to fit a schema that is up to date
+            if current {
+                db.check_statements()?;
+                println!("statements fit the schema");
+            }
+            Ok(current)
+        })
+    } else {
+        db.migrate().map(|applied| {
+            if applied.is_empty() {
+                println!("schema is up to date");
+            } else {
+                println!("applied migrations {:?}", applied);
+            }
+            true
+        })
+    };
+    close_db(db, &config);
+
+    match result {
+        Ok(true) => Ok(()),
+        Ok(false) => bail!("schema is not up to date"),
+        Err(e) => bail!("migration failed: {}", e),
+    }
+}
+
+fn check_config(config: ServerConfig) -> anyhow::Result<()> {
+    print!("{}", config.to_redacted_toml()?);
+    eprintln!("config OK");
+    Ok(())
+}
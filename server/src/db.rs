@@ -1,14 +1,25 @@
-use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::sync::mpsc::{channel, Receiver};
 use std::sync::{Arc, Mutex};
-use std::thread;
 use std::time::{Duration, Instant};
-use sqlx::postgres::{PgPoolOptions, PgRow};
-use sqlx::{Pool, Postgres, Row as SqlxRow};
-use gns::GnsConnection;
+use std::str::FromStr;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
+use sqlx::sqlite::{SqliteConnectOptions, SqlitePoolOptions};
+use sqlx::{Pool, Postgres, Sqlite};
+
+use crate::migrations::{self, MigrationStatus};
+use crate::rows::{decode_pg_row, decode_rows, decode_sqlite_row, DbRow, FromDbRow};
+use crate::statements::{self, StatementCatalog};
+pub use crate::worker_pool::{CancelHandle, PoolStats, QueueError};
+use crate::worker_pool::{LaneConfig, PoolConfig, WorkerPool};
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum DbStmt {
     GetUser,
+    GetLockouts,
+    SaveLockout,
+    CreateUser,
+    UpdatePasswordHash,
     Custom(String),
 }
 
@@ -16,288 +27,1016 @@ impl DbStmt {
     pub fn as_str(&self) -> &str {
         match self {
             DbStmt::GetUser => "get_user",
+            DbStmt::GetLockouts => "get_lockouts",
+            DbStmt::SaveLockout => "save_lockout",
+            DbStmt::CreateUser => "create_user",
+            DbStmt::UpdatePasswordHash => "update_password_hash",
             DbStmt::Custom(s) => s,
         }
     }
 
-    pub fn from_str(s: &str) -> Self {
+    /// The inverse of as_str, unknown names become Custom.
+    pub fn from_name(s: &str) -> Self {
         match s {
             "get_user" => DbStmt::GetUser,
+            "get_lockouts" => DbStmt::GetLockouts,
+            "save_lockout" => DbStmt::SaveLockout,
+            "create_user" => DbStmt::CreateUser,
+            "update_password_hash" => DbStmt::UpdatePasswordHash,
             other => DbStmt::Custom(other.to_string()),
         }
     }
+
+    /// The lane queue_job puts this statement in. Logins wait on GetUser and CreateUser,
+    /// everything else can afford to queue behind them.
+    pub fn priority(&self) -> DbPriority {
+        match self {
+            DbStmt::GetUser | DbStmt::CreateUser => DbPriority::Interactive,
+            DbStmt::GetLockouts | DbStmt::SaveLockout | DbStmt::UpdatePasswordHash => DbPriority::Normal,
+            DbStmt::Custom(_) => DbPriority::Normal,
+        }
+    }
+
+    /// Safe to run again after a transient failure, even if the first try may have
+    /// reached the database. CreateUser is not: a lost reply followed by a retry
+    /// would report the name as taken. Unknown custom statements never are.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            DbStmt::GetUser | DbStmt::GetLockouts | DbStmt::SaveLockout | DbStmt::UpdatePasswordHash => true,
+            DbStmt::CreateUser | DbStmt::Custom(_) => false,
+        }
+    }
+}
+
+/// Scheduling class of a DB job. Each has its own queue, see DbWorkerConfig::lanes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DbPriority {
+    /// A player is waiting on it.
+    Interactive,
+    Normal,
+    /// Periodic saves, audit rows, statistics.
+    Background,
+}
+
+impl DbPriority {
+    /// In lane order.
+    pub const ALL: [DbPriority; 3] = [DbPriority::Interactive, DbPriority::Normal, DbPriority::Background];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DbPriority::Interactive => "interactive",
+            DbPriority::Normal => "normal",
+            DbPriority::Background => "background",
+        }
+    }
+
+    fn lane(self) -> usize {
+        self as usize
+    }
+}
+
+/// Which database a connection string points at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DbBackend {
+    Postgres,
+    /// File or in-memory database, for local development and tests.
+    Sqlite,
+}
+
+impl DbBackend {
+    /// `postgres://...`, `postgresql://...`, `sqlite://path`, `sqlite:path` or `sqlite::memory:`.
+    pub fn from_url(url: &str) -> Option<Self> {
+        if url.starts_with("postgres://") || url.starts_with("postgresql://") {
+            Some(DbBackend::Postgres)
+        } else if url.starts_with("sqlite:") {
+            Some(DbBackend::Sqlite)
+        } else {
+            None
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DbBackend::Postgres => "postgres",
+            DbBackend::Sqlite => "sqlite",
+        }
+    }
+}
+
+/// Why a statement failed, and whether running it again could help.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DbError {
+    /// Connection lost, pool exhausted, database restarting, locked or busy.
+    Transient(String),
+    /// Took longer than the statement timeout.
+    Timeout(Duration),
+    /// Retrying won't help: bad SQL, constraint violations, unreadable rows.
+    Failed(String),
+}
+
+impl DbError {
+    /// Sorts a driver error by whether it is worth retrying.
+    pub fn from_sqlx(e: sqlx::Error, backend: DbBackend) -> Self {
+        let transient = match &e {
+            sqlx::Error::Io(_)
+            | sqlx::Error::Tls(_)
+            | sqlx::Error::PoolTimedOut
+            | sqlx::Error::PoolClosed
+            | sqlx::Error::WorkerCrashed => true,
+            sqlx::Error::Database(db) => db.code().is_some_and(|code| match backend {
+                // Connection exceptions, serialization failure, deadlock, too many
+                // connections, admin or crash shutdown and "cannot connect now"
+                DbBackend::Postgres => {
+                    code.starts_with("08")
+                        || matches!(&*code, "40001" | "40P01" | "53300" | "57P01" | "57P02" | "57P03")
+                }
+                // SQLITE_BUSY and SQLITE_LOCKED, including their extended codes
+                DbBackend::Sqlite => code.parse::<i32>().is_ok_and(|code| matches!(code & 0xff, 5 | 6)),
+            }),
+            _ => false,
+        };
+        if transient {
+            DbError::Transient(e.to_string())
+        } else {
+            DbError::Failed(e.to_string())
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, DbError::Transient(_) | DbError::Timeout(_))
+    }
+
+    /// Same kind, with `context` in front of the message.
+    fn context(self, context: &str) -> Self {
+        match self {
+            DbError::Transient(e) => DbError::Transient(format!("{}: {}", context, e)),
+            DbError::Failed(e) => DbError::Failed(format!("{}: {}", context, e)),
+            timeout => timeout,
+        }
+    }
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Transient(e) => write!(f, "{} (transient)", e),
+            DbError::Timeout(after) => write!(f, "statement timed out after {:?}", after),
+            DbError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+/// Runs `fut` with a deadline, a timeout counts as a transient failure. The server enforces
+/// the statement timeout too (see `DbPool::connect`), this also covers a hung connection.
+async fn with_timeout<R>(timeout: Duration, fut: impl Future<Output = Result<R, DbError>>) -> Result<R, DbError> {
+    tokio::time::timeout(timeout, fut)
+        .await
+        .unwrap_or(Err(DbError::Timeout(timeout)))
+}
+
+/// Connection pool of either backend. Every statement binds text parameters and returns
+/// DbRows, so the rest of the server does not care which one it is.
+#[derive(Debug, Clone)]
+pub enum DbPool {
+    Postgres(Pool<Postgres>),
+    Sqlite(Pool<Sqlite>),
+}
+
+impl DbPool {
+    /// Opens the pool and one connection. `acquire_timeout` bounds how long a job
+    /// waits for a connection, so an unreachable database fails jobs instead of
+    /// piling them up. `statement_timeout` is set on every connection, so the server
+    /// cancels a slow statement instead of running it on after the client gave up.
+    pub async fn connect(
+        url: &str,
+        max_connections: u32,
+        acquire_timeout: Duration,
+        statement_timeout: Duration,
+    ) -> Result<Self, DbError> {
+        match DbBackend::from_url(url) {
+            Some(DbBackend::Postgres) => {
+                let options = PgConnectOptions::from_str(url)
+                    .map_err(|e| DbError::Failed(e.to_string()))?
+                    .options([("statement_timeout", statement_timeout.as_millis())]);
+                PgPoolOptions::new()
+                    .max_connections(max_connections)
+                    .acquire_timeout(acquire_timeout)
+                    .connect_with(options)
+                    .await
+                    .map(DbPool::Postgres)
+                    .map_err(|e| DbError::from_sqlx(e, DbBackend::Postgres))
+            }
+            Some(DbBackend::Sqlite) => {
+                // SQLite has no statement timeout, but waiting on another writer's lock is bounded
+                let options = SqliteConnectOptions::from_str(url)
+                    .map_err(|e| DbError::Failed(e.to_string()))?
+                    .create_if_missing(true)
+                    .busy_timeout(statement_timeout);
+                // Every connection to :memory: is its own empty database, so keep exactly one alive
+                let in_memory = url.contains(":memory:") || url.contains("mode=memory");
+                let pool = if in_memory {
+                    SqlitePoolOptions::new()
+                        .max_connections(1)
+                        .min_connections(1)
+                        .idle_timeout(None)
+                        .max_lifetime(None)
+                } else {
+                    SqlitePoolOptions::new().max_connections(max_connections)
+                };
+                pool.acquire_timeout(acquire_timeout)
+                    .connect_with(options)
+                    .await
+                    .map(DbPool::Sqlite)
+                    .map_err(|e| DbError::from_sqlx(e, DbBackend::Sqlite))
+            }
+            None => Err(DbError::Failed(format!(
+                "unsupported database url, expected postgres:// or sqlite: ({})",
+                url
+            ))),
+        }
+    }
+
+    pub fn backend(&self) -> DbBackend {
+        match self {
+            DbPool::Postgres(_) => DbBackend::Postgres,
+            DbPool::Sqlite(_) => DbBackend::Sqlite,
+        }
+    }
+
+    /// Runs one statement with text parameters and decodes every row.
+    pub async fn fetch_rows(&self, sql: &str, params: &[String], timeout: Duration) -> Result<Vec<DbRow>, DbError> {
+        let backend = self.backend();
+        let sqlx_error = |e| DbError::from_sqlx(e, backend);
+        with_timeout(timeout, async {
+            match self {
+                DbPool::Postgres(pool) => {
+                    let mut query = sqlx::query(sql);
+                    for param in params {
+                        query = query.bind(param);
+                    }
+                    let rows = query.fetch_all(pool).await.map_err(sqlx_error)?;
+                    decode_rows(&rows, decode_pg_row).map_err(DbError::Failed)
+                }
+                DbPool::Sqlite(pool) => {
+                    let mut query = sqlx::query(sql);
+                    for param in params {
+                        query = query.bind(param);
+                    }
+                    let rows = query.fetch_all(pool).await.map_err(sqlx_error)?;
+                    decode_rows(&rows, decode_sqlite_row).map_err(DbError::Failed)
+                }
+            }
+        })
+        .await
+    }
+
+    /// Runs `(sql, step)` pairs in one transaction, each step with its own `timeout`.
+    /// Any failing step, or a step with `require_rows` that returned nothing, rolls
+    /// everything back.
+    pub async fn run_transaction(&self, steps: &[(String, DbStep)], timeout: Duration) -> Result<Vec<Vec<DbRow>>, DbError> {
+        let backend = self.backend();
+        let sqlx_error = |e| DbError::from_sqlx(e, backend);
+        let step_context = |i: usize, step: &DbStep| format!("step {} ({}) failed", i + 1, step.stmt.as_str());
+        let mut results = Vec::with_capacity(steps.len());
+        match self {
+            DbPool::Postgres(pool) => {
+                let mut tx = with_timeout(timeout, async { pool.begin().await.map_err(sqlx_error) }).await?;
+                for (i, (sql, step)) in steps.iter().enumerate() {
+                    let mut query = sqlx::query(sql);
+                    for param in &step.params {
+                        query = query.bind(param);
+                    }
+                    let rows = with_timeout(timeout, async {
+                        let rows = query.fetch_all(&mut *tx).await.map_err(sqlx_error)?;
+                        decode_rows(&rows, decode_pg_row).map_err(DbError::Failed)
+                    })
+                    .await
+                    .map_err(|e| e.context(&step_context(i, step)))?;
+                    if step.require_rows && rows.is_empty() {
+                        // Dropping tx rolls it back
+                        return Err(DbError::Failed(format!("{}: no rows", step_context(i, step))));
+                    }
+                    results.push(rows);
+                }
+                with_timeout(timeout, async { tx.commit().await.map_err(sqlx_error) }).await?;
+            }
+            DbPool::Sqlite(pool) => {
+                let mut tx = with_timeout(timeout, async { pool.begin().await.map_err(sqlx_error) }).await?;
+                for (i, (sql, step)) in steps.iter().enumerate() {
+                    let mut query = sqlx::query(sql);
+                    for param in &step.params {
+                        query = query.bind(param);
+                    }
+                    let rows = with_timeout(timeout, async {
+                        let rows = query.fetch_all(&mut *tx).await.map_err(sqlx_error)?;
+                        decode_rows(&rows, decode_sqlite_row).map_err(DbError::Failed)
+                    })
+                    .await
+                    .map_err(|e| e.context(&step_context(i, step)))?;
+                    if step.require_rows && rows.is_empty() {
+                        return Err(DbError::Failed(format!("{}: no rows", step_context(i, step))));
+                    }
+                    results.push(rows);
+                }
+                with_timeout(timeout, async { tx.commit().await.map_err(sqlx_error) }).await?;
+            }
+        }
+        Ok(results)
+    }
 }
 
+/// One statement of a transaction.
 #[derive(Debug, Clone)]
-pub struct DbJob {
-    pub id: u64,
+pub struct DbStep {
     pub stmt: DbStmt,
     pub params: Vec<String>,
-    pub respond_to: GnsConnection,
+    /// Roll the whole transaction back when this step returns no rows. Use with
+    /// `UPDATE ... WHERE gold >= $2 RETURNING id` style guards.
+    pub require_rows: bool,
+}
+
+impl DbStep {
+    pub fn new(stmt: DbStmt, params: Vec<String>) -> Self {
+        Self { stmt, params, require_rows: false }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum DbWork {
+    /// `DbJob.stmt` with these params, on its own.
+    Query(Vec<String>),
+    /// Every step in order inside one transaction, `DbJob.stmt` only labels the result.
+    Transaction(Vec<DbStep>),
+}
+
+/// A queued statement or transaction. `token` is whatever the caller needs to route
+/// the result, it comes back untouched in DbResult.
+#[derive(Debug, Clone)]
+pub struct DbJob<T> {
+    pub stmt: DbStmt,
+    pub work: DbWork,
+    pub token: T,
 }
 
 #[derive(Debug, Clone)]
-pub struct DbResult {
-    pub id: u64,
+pub struct DbResult<T> {
     pub stmt: DbStmt,
     pub success: bool,
-    pub rows: Option<Vec<Vec<String>>>,
-    pub message: Option<String>,
-    pub respond_to: GnsConnection,
+    pub rows: Option<Vec<DbRow>>,
+    /// Rows of every step of a transaction, in order. Empty for single queries
+    /// and for transactions that were rolled back.
+    pub steps: Vec<Vec<DbRow>>,
+    /// Set when `success` is false, after any retries. For a rolled back transaction
+    /// it names the step that failed.
+    pub error: Option<DbError>,
+    pub token: T,
 }
 
-struct WorkerThread {
-    pending_count: usize,
-    idle_calls: usize,
-    last_activity: Instant,
+impl<T> DbResult<T> {
+    pub fn error_message(&self) -> String {
+        self.error.as_ref().map(ToString::to_string).unwrap_or_default()
+    }
+
+    /// Decodes the rows into the statement's row type, e.g. UserRow for GetUser.
+    pub fn decode<R: FromDbRow>(&self) -> Result<Vec<R>, String> {
+        self.rows
+            .as_deref()
+            .unwrap_or_default()
+            .iter()
+            .map(R::from_row)
+            .collect()
+    }
 }
 
-struct WorkerState {
-    input_queue: VecDeque<DbJob>,
-    output_queue: VecDeque<DbResult>,
-    workers: Vec<WorkerThread>,
-    pool: Pool<Postgres>,
-    prepared: HashMap<DbStmt, String>,
-    max_pending_per_thread: usize,
-    max_idle_calls: usize,
-    next_worker_id: usize,
+/// What the database looks like from the results of recent jobs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DbHealth {
+    Healthy,
+    /// Some jobs failed with transient errors, the rest still get through.
+    Degraded,
+    /// Every recent job failed. New jobs are refused, except one probe per
+    /// `probe_interval` that finds out whether it came back.
+    Down,
 }
 
-pub struct DbWorker {
-    state: Arc<Mutex<WorkerState>>,
+impl DbHealth {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DbHealth::Healthy => "healthy",
+            DbHealth::Degraded => "degraded",
+            DbHealth::Down => "down",
+        }
+    }
 }
 
-impl DbWorker {
-    pub fn new(conn_str: &str, prepared: HashMap<DbStmt, &str>) -> Self {
-        let conn_str = conn_str.to_string();
-        let prepared_map: HashMap<DbStmt, String> = prepared
-            .into_iter()
-            .map(|(k, v)| (k, v.to_string()))
-            .collect();
-
-        // Create pool in a blocking context
-        let pool = std::thread::spawn(move || {
-            let runtime = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();
-
-            runtime.block_on(async {
-                PgPoolOptions::new()
-                    .max_connections(16)
-                    .connect(&conn_str)
-                    .await
-                    .expect("Failed to create pool")
-            })
-        })
-            .join()
-            .unwrap();
+struct HealthState {
+    /// Jobs in a row that failed with a transient error after all their retries.
+    consecutive_failures: u32,
+    last_error: Option<DbError>,
+    last_probe: Option<Instant>,
+}
 
-        let state = Arc::new(Mutex::new(WorkerState {
-            input_queue: VecDeque::new(),
-            output_queue: VecDeque::new(),
-            workers: Vec::new(),
-            pool,
-            prepared: prepared_map,
-            max_pending_per_thread: 5,
-            max_idle_calls: 25,
-            next_worker_id: 0,
-        }));
+/// What the worker threads share besides the queue, only the health changes after start.
+struct Shared {
+    /// Drives the pool for every call, workers and sync calls alike. sqlx hands connections
+    /// back through tasks on the runtime that used them, so a runtime dropped after one call
+    /// takes its connections along, and with them any `:memory:` database.
+    runtime: tokio::runtime::Runtime,
+    pool: DbPool,
+    catalog: StatementCatalog,
+    health: Mutex<HealthState>,
+    config: DbWorkerConfig,
+}
 
-        // Spawn initial worker thread
-        Self::spawn_worker_thread(state.clone(), 0);
+impl Shared {
+    fn health(&self) -> DbHealth {
+        let failures = self.health.lock().unwrap().consecutive_failures;
+        if failures == 0 {
+            DbHealth::Healthy
+        } else if failures < self.config.down_after_failures {
+            DbHealth::Degraded
+        } else {
+            DbHealth::Down
+        }
+    }
 
-        Self { state }
+    /// Feeds a finished job into the health. Only transient errors count, a constraint
+    /// violation still means the database answered.
+    fn record(&self, outcome: Result<(), &DbError>) {
+        let before = self.health();
+        {
+            let mut health = self.health.lock().unwrap();
+            match outcome {
+                Err(e) if e.is_transient() => {
+                    health.consecutive_failures += 1;
+                    health.last_error = Some(e.clone());
+                }
+                _ => health.consecutive_failures = 0,
+            }
+        }
+        let after = self.health();
+        if after != before {
+            match after {
+                DbHealth::Healthy => log::info!("Database is {}", after.as_str()),
+                _ => log::warn!("Database is {}: {}", after.as_str(), self.last_error().unwrap_or_default()),
+            }
+        }
     }
 
-    fn spawn_worker_thread(state: Arc<Mutex<WorkerState>>, worker_id: usize) {
-        thread::spawn(move || {
-            let runtime = tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .unwrap();
+    fn last_error(&self) -> Option<String> {
+        self.health.lock().unwrap().last_error.as_ref().map(ToString::to_string)
+    }
 
-            runtime.block_on(async {
-                loop {
-                    thread::sleep(Duration::from_millis(5));
+    /// Whether a new job may be queued. While down only one probe gets through per interval.
+    fn admit(&self) -> bool {
+        if self.health() != DbHealth::Down {
+            return true;
+        }
+        let mut health = self.health.lock().unwrap();
+        let due = health.last_probe.is_none_or(|at| at.elapsed() >= self.config.probe_interval);
+        if due {
+            health.last_probe = Some(Instant::now());
+        }
+        due
+    }
+}
 
-                    let job_opt = {
-                        let mut state = state.lock().unwrap();
+/// Pool and worker thread sizing.
+#[derive(Debug, Clone, Copy)]
+pub struct DbWorkerConfig {
+    /// Also the upper bound on worker threads, more could only wait for a connection.
+    pub max_connections: u32,
+    /// Worker threads kept alive while idle.
+    pub min_threads: usize,
+    /// Queued jobs per thread before another thread is spawned.
+    pub max_pending_per_thread: usize,
+    /// How long a surplus thread waits for work before it exits.
+    pub idle_timeout: Duration,
+    /// Indexed by DbPriority.
+    pub lanes: [DbLaneLimits; 3],
+    /// Limit for every single statement, including each step of a transaction.
+    pub statement_timeout: Duration,
+    /// How long a statement waits for a free connection, part of its statement_timeout.
+    pub acquire_timeout: Duration,
+    /// Extra tries for idempotent statements that failed with a transient error.
+    pub max_retries: u32,
+    /// Time a job may spend on all its tries together, backoff included. Later tries only
+    /// get what is left of it, so a login's GetUser gives up before its auth deadline.
+    pub retry_budget: Duration,
+    /// Wait before the first retry, doubled for every further one.
+    pub retry_backoff: Duration,
+    /// Tries to reach the database on startup before giving up.
+    pub connect_attempts: u32,
+    /// Wait after the first failed connect, doubled up to `max_backoff`.
+    pub connect_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Jobs failing in a row before the database counts as down.
+    pub down_after_failures: u32,
+    /// How often a job is let through as a probe while the database is down.
+    pub probe_interval: Duration,
+}
 
-                        // Check if this worker should be terminated
-                        if worker_id < state.workers.len() {
-                            if state.workers[worker_id].idle_calls >= state.max_idle_calls
-                                && state.workers.len() > 1 {
-                                // Mark for removal
-                                state.workers.remove(worker_id);
-                                println!("Worker {} terminated due to inactivity", worker_id);
-                                return;
-                            }
-                        }
+/// Share of the workers and queue limit of one DbPriority.
+#[derive(Debug, Clone, Copy)]
+pub struct DbLaneLimits {
+    /// Relative to the other lanes while more than one has work waiting.
+    pub weight: u32,
+    /// Jobs that may wait in the lane before queue_job reports Full.
+    pub queue_capacity: usize,
+}
 
-                        state.input_queue.pop_front()
-                    };
-
-                    if let Some(job) = job_opt {
-                        let pool = {
-                            let state = state.lock().unwrap();
-                            state.pool.clone()
-                        };
-
-                        let prepared = {
-                            let state = state.lock().unwrap();
-                            state.prepared.clone()
-                        };
-
-                        // Update worker stats
-                        {
-                            let mut state = state.lock().unwrap();
-                            if worker_id < state.workers.len() {
-                                state.workers[worker_id].pending_count += 1;
-                                state.workers[worker_id].last_activity = Instant::now();
-                            }
-                        }
+impl Default for DbWorkerConfig {
+    fn default() -> Self {
+        Self {
+            max_connections: 16,
+            min_threads: 1,
+            max_pending_per_thread: 5,
+            idle_timeout: Duration::from_secs(10),
+            lanes: [
+                DbLaneLimits { weight: 8, queue_capacity: 256 },
+                DbLaneLimits { weight: 3, queue_capacity: 1024 },
+                DbLaneLimits { weight: 1, queue_capacity: 4096 },
+            ],
+            statement_timeout: Duration::from_secs(5),
+            acquire_timeout: Duration::from_secs(2),
+            max_retries: 2,
+            retry_budget: Duration::from_secs(8),
+            retry_backoff: Duration::from_millis(100),
+            connect_attempts: 5,
+            connect_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(10),
+            down_after_failures: 5,
+            probe_interval: Duration::from_secs(5),
+        }
+    }
+}
 
-                        let result = Self::execute_job(&pool, &prepared, job).await;
+impl DbWorkerConfig {
+    /// `base` doubled `attempt` times, at most `max_backoff`.
+    fn backoff(&self, base: Duration, attempt: u32) -> Duration {
+        base.saturating_mul(1 << attempt.min(16)).min(self.max_backoff)
+    }
 
-                        // Update worker stats and push result via callback
-                        {
-                            let mut state = state.lock().unwrap();
-                            if worker_id < state.workers.len() {
-                                state.workers[worker_id].pending_count =
-                                    state.workers[worker_id].pending_count.saturating_sub(1);
-                            }
-                            state.output_queue.push_back(result);
-                        }
-                    } else {
-                        // No job available, increment idle counter
-                        let mut state = state.lock().unwrap();
-                        if worker_id < state.workers.len() {
-                            state.workers[worker_id].idle_calls += 1;
-                        }
+    fn pool_config(&self) -> PoolConfig {
+        PoolConfig {
+            name: "DB",
+            min_threads: self.min_threads,
+            max_threads: self.max_connections as usize,
+            max_pending_per_thread: self.max_pending_per_thread,
+            idle_timeout: self.idle_timeout,
+            lanes: DbPriority::ALL
+                .iter()
+                .map(|priority| {
+                    let limits = self.lanes[priority.lane()];
+                    LaneConfig {
+                        name: priority.as_str(),
+                        weight: limits.weight,
+                        queue_capacity: limits.queue_capacity,
                     }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Runs statements on a pool of worker threads. `T` is the caller's correlation token,
+/// the admin commands that only use the sync calls leave it at `()`.
+pub struct DbWorker<T: Send + 'static = ()> {
+    shared: Arc<Shared>,
+    workers: WorkerPool<DbJob<T>>,
+    results: Mutex<Receiver<DbResult<T>>>,
+}
+
+impl<T: Send + 'static> DbWorker<T> {
+    /// Connects, retrying transient failures `config.connect_attempts` times with backoff,
+    /// and starts the workers. Errors out instead of panicking when the database stays
+    /// unreachable or refuses the connection outright.
+    pub fn new(conn_str: &str, config: DbWorkerConfig) -> Result<Self, DbError> {
+
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .worker_threads(2)
+            .thread_name("db-runtime")
+            .enable_all()
+            .build()
+            .map_err(|e| DbError::Failed(e.to_string()))?;
+
+        let pool = runtime.block_on(async {
+            let attempts = config.connect_attempts.max(1);
+            let mut attempt = 0;
+            loop {
+                match DbPool::connect(conn_str, config.max_connections, config.acquire_timeout, config.statement_timeout).await {
+                    Ok(pool) => break Ok(pool),
+                    Err(e) if e.is_transient() && attempt + 1 < attempts => {
+                        let wait = config.backoff(config.connect_backoff, attempt);
+                        attempt += 1;
+                        log::warn!("Could not reach the database ({}/{}), retrying in {:?}: {}", attempt, attempts, wait, e);
+                        tokio::time::sleep(wait).await;
+                    }
+                    Err(e) => break Err(e),
                 }
-            });
+            }
+        })?;
+
+        let catalog = StatementCatalog::load(pool.backend());
+        let (results_tx, results_rx) = channel();
+        let shared = Arc::new(Shared {
+            runtime,
+            pool,
+            catalog,
+            health: Mutex::new(HealthState { consecutive_failures: 0, last_error: None, last_probe: None }),
+            config,
+        });
+
+        let handler_shared = shared.clone();
+        let workers = WorkerPool::new(config.pool_config(), move |_| {
+            let shared = handler_shared.clone();
+            let results = results_tx.clone();
+
+            Box::new(move |job| {
+                let result = shared.runtime.block_on(Self::execute_job(&shared, job));
+                shared.record(result.error.as_ref().map_or(Ok(()), Err));
+                // The receiver only goes away with the DbWorker itself
+                let _ = results.send(result);
+            })
         });
+
+        Ok(Self {
+            shared,
+            workers,
+            results: Mutex::new(results_rx),
+        })
+    }
+
+    /// Stops taking jobs, lets the workers finish what is already queued and joins them.
+    /// Returns false if they didn't all exit within `timeout`.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        self.workers.shutdown(timeout)
     }
 
-    async fn execute_job(
-        pool: &Pool<Postgres>,
-        prepared: &HashMap<DbStmt, String>,
-        job: DbJob,
-    ) -> DbResult {
-        println!("Sent job for execution");
-        if let Some(sql) = prepared.get(&job.stmt) {
-            let query = sqlx::query(sql);
+    async fn execute_job(shared: &Shared, job: DbJob<T>) -> DbResult<T> {
+        let DbJob { stmt, work, token } = job;
+        let mut result = DbResult {
+            stmt,
+            success: false,
+            rows: None,
+            steps: Vec::new(),
+            error: None,
+            token,
+        };
 
-            // Bind parameters
-            let mut query = query;
-            for param in &job.params {
-                query = query.bind(param);
+        match work {
+            DbWork::Query(params) => {
+                let Some(sql) = shared.catalog.sql(&result.stmt) else {
+                    result.error = Some(DbError::Failed("Unknown statement".into()));
+                    return result;
+                };
+                let retry = result.stmt.is_idempotent();
+                let outcome = Self::with_retries(&shared.config, retry, |timeout| {
+                    shared.pool.fetch_rows(sql, &params, timeout)
+                })
+                .await;
+                match outcome {
+                    Ok(rows) => {
+                        result.success = true;
+                        result.rows = Some(rows);
+                    }
+                    Err(e) => result.error = Some(e),
+                }
             }
-            println!("Got statement");
-
-            match query.fetch_all(pool).await {
-                Ok(rows) => DbResult {
-                    id: job.id,
-                    success: true,
-                    stmt: job.stmt,
-                    respond_to: job.respond_to,
-                    rows: Some(Self::rows_to_strings(rows)),
-                    message: None,
-                },
-                Err(e) => DbResult {
-                    id: job.id,
-                    success: false,
-                    stmt: job.stmt,
-                    respond_to: job.respond_to,
-                    rows: None,
-                    message: Some(e.to_string()),
-                },
+            DbWork::Transaction(steps) => {
+                // Resolve every statement first so an unknown one never starts a transaction
+                let mut resolved = Vec::with_capacity(steps.len());
+                for step in steps {
+                    match shared.catalog.sql(&step.stmt) {
+                        Some(sql) => resolved.push((sql.to_string(), step)),
+                        None => {
+                            let message = format!("Unknown statement {}", step.stmt.as_str());
+                            result.error = Some(DbError::Failed(message));
+                            return result;
+                        }
+                    }
+                }
+
+                // A failed transaction was rolled back, so it may run again if every step could
+                let retry = resolved.iter().all(|(_, step)| step.stmt.is_idempotent());
+                let outcome = Self::with_retries(&shared.config, retry, |timeout| {
+                    shared.pool.run_transaction(&resolved, timeout)
+                })
+                .await;
+                match outcome {
+                    Ok(steps) => {
+                        result.success = true;
+                        result.steps = steps;
+                    }
+                    Err(e) => result.error = Some(e),
+                }
             }
+        }
+        result
+    }
 
-        } else {
-            DbResult {
-                id: job.id,
-                success: false,
-                stmt: job.stmt,
-                respond_to: job.respond_to,
-                rows: None,
-                message: Some("Unknown statement".into()),
+    /// Runs `attempt` once, or up to `max_retries` more times with backoff while it
+    /// fails transiently and `retry` allows it. Every try gets the statement timeout,
+    /// cut down to what is left of `retry_budget`, and none starts once it is spent.
+    async fn with_retries<R, F, Fut>(config: &DbWorkerConfig, retry: bool, mut attempt: F) -> Result<R, DbError>
+    where
+        F: FnMut(Duration) -> Fut,
+        Fut: Future<Output = Result<R, DbError>>,
+    {
+        let deadline = Instant::now() + config.retry_budget;
+        let mut tries = 0;
+        loop {
+            let timeout = config.statement_timeout.min(deadline.saturating_duration_since(Instant::now()));
+            match attempt(timeout).await {
+                Err(e) if retry && e.is_transient() && tries < config.max_retries => {
+                    let wait = config.backoff(config.retry_backoff, tries);
+                    if Instant::now() + wait >= deadline {
+                        log::debug!("Retry budget of {:?} spent after {} tries: {}", config.retry_budget, tries + 1, e);
+                        return Err(e);
+                    }
+                    tries += 1;
+                    log::debug!("Retrying after transient error ({}/{}) in {:?}: {}", tries, config.max_retries, wait, e);
+                    tokio::time::sleep(wait).await;
+                }
+                outcome => return outcome,
             }
         }
     }
 
-    fn rows_to_strings(rows: Vec<PgRow>) -> Vec<Vec<String>> {
-        rows.iter()
-            .map(|row| {
-                (0..row.len())
-                    .map(|i| {
-                        row.try_get::<String, _>(i)
-                            .unwrap_or_else(|_| "<NULL>".to_string())
-                    })
-                    .collect::<Vec<String>>()
-            })
-            .collect()
+    /// Queues `stmt` in the lane of its DbStmt::priority.
+    pub fn queue_job(&self, stmt: DbStmt, params: Vec<String>, token: T) -> Result<CancelHandle, QueueError> {
+        self.queue_job_in(stmt.priority(), stmt, params, token)
     }
 
-    pub fn queue_job(&self, id: u64, stmt: DbStmt, params: Vec<String>, respond_to: GnsConnection) -> bool {
-        println!("Entered queue");
+    pub fn queue_job_in(&self, priority: DbPriority, stmt: DbStmt, params: Vec<String>, token: T) -> Result<CancelHandle, QueueError> {
+        self.enqueue(priority, DbJob { stmt, work: DbWork::Query(params), token })
+    }
 
-        let job = DbJob { id, stmt, params, respond_to };
+    /// Queues `steps` to run atomically. The result comes back as one DbResult labelled
+    /// `stmt`, with the rows of every step in `DbResult.steps`.
+    pub fn queue_transaction(&self, stmt: DbStmt, steps: Vec<DbStep>, token: T) -> Result<CancelHandle, QueueError> {
+        self.enqueue(stmt.priority(), DbJob { stmt, work: DbWork::Transaction(steps), token })
+    }
 
-        if let Ok(mut state) = self.state.try_lock() {
-            // Find worker with least pending jobs
-            let min_pending_worker = state.workers.iter()
-                .enumerate()
-                .min_by_key(|(_, w)| w.pending_count)
-                .map(|(idx, w)| (idx, w.pending_count));
+    fn enqueue(&self, priority: DbPriority, job: DbJob<T>) -> Result<CancelHandle, QueueError> {
+        // Fail fast while the database is down instead of queueing jobs that would only time out
+        if !self.shared.admit() {
+            return Err(QueueError::Unavailable);
+        }
+        let checked = match &job.work {
+            DbWork::Query(params) => self.shared.catalog.check_params(&job.stmt, params),
+            DbWork::Transaction(steps) => steps
+                .iter()
+                .try_for_each(|step| self.shared.catalog.check_params(&step.stmt, &step.params)),
+        };
+        if let Err(e) = checked {
+            log::warn!("Refusing {} job: {}", job.stmt.as_str(), e);
+            return Err(QueueError::Invalid);
+        }
+        self.workers.submit_to(priority.lane(), job)
+    }
 
-            // Check if we need to spawn a new worker
-            if let Some((_, min_pending)) = min_pending_worker {
-                if min_pending >= state.max_pending_per_thread {
-                    // Spawn new worker
-                    let new_id = state.next_worker_id;
-                    state.next_worker_id += 1;
+    pub fn health(&self) -> DbHealth {
+        self.shared.health()
+    }
 
-                    state.workers.push(WorkerThread {
-                        pending_count: 0,
-                        idle_calls: 0,
-                        last_activity: Instant::now(),
-                    });
+    /// The transient error that last counted against the health, if any.
+    pub fn last_error(&self) -> Option<String> {
+        self.shared.last_error()
+    }
 
-                    println!("Spawning new worker thread {}", new_id);
-                    Self::spawn_worker_thread(self.state.clone(), new_id);
-                }
-            } else {
-                // No workers, spawn first one
-                state.workers.push(WorkerThread {
-                    pending_count: 0,
-                    idle_calls: 0,
-                    last_activity: Instant::now(),
-                });
-                Self::spawn_worker_thread(self.state.clone(), 0);
-            }
+    /// Jobs waiting for a worker.
+    pub fn queue_len(&self) -> usize {
+        self.workers.queue_len()
+    }
 
-            // Reset idle calls for all workers when there's work
-            for worker in &mut state.workers {
-                worker.idle_calls = 0;
-            }
+    pub fn stats(&self) -> PoolStats {
+        self.workers.stats()
+    }
+
+    /// Runs a statement on the calling thread and waits for it, for startup loading
+    /// and other places that are not driven by a client connection.
+    pub fn query_sync(&self, stmt: DbStmt, params: Vec<String>) -> Result<Vec<DbRow>, String> {
+        self.shared.catalog.check_params(&stmt, &params)?;
+        let sql = self.shared.catalog.sql(&stmt).unwrap_or_default();
+
+        let timeout = self.shared.config.statement_timeout;
+        self.block_on_pool(move |pool| async move {
+            pool.fetch_rows(sql, &params, timeout).await.map_err(|e| e.to_string())
+        })
+    }
+
+    pub fn backend(&self) -> DbBackend {
+        self.shared.pool.backend()
+    }
+
+    /// Applies pending schema migrations, see the migrations module.
+    pub fn migrate(&self) -> Result<Vec<i32>, String> {
+        self.block_on_pool(|pool| async move { migrations::apply(&pool).await })
+    }
+
+    pub fn migration_status(&self) -> Result<MigrationStatus, String> {
+        self.block_on_pool(|pool| async move { migrations::status(&pool).await })
+    }
 
-            state.input_queue.push_back(job);
-            println!("Awaited issue queue");
-            true
+    /// Prepares every catalog statement against the live schema, see statements::validate.
+    pub fn check_statements(&self) -> Result<(), String> {
+        let catalog = self.shared.catalog.clone();
+        self.block_on_pool(move |pool| async move { statements::validate(&pool, &catalog).await })
+    }
+
+    /// Brings the schema up to date when `auto_migrate` is set, otherwise only checks
+    /// that it is, then checks the statements against it. Called once before the server
+    /// starts using the database.
+    pub fn prepare_schema(&self, auto_migrate: bool) -> Result<(), String> {
+        if auto_migrate {
+            self.migrate()?;
         } else {
-            // Could not acquire lock, return false
-            false
+            let status = self.migration_status()?;
+            if let Some(drift) = status.drift.first() {
+                return Err(format!("schema drift: {}", drift));
+            }
+            if !status.pending.is_empty() {
+                return Err(format!("pending migrations {:?}, run `server migrate`", status.pending));
+            }
         }
+        self.check_statements()
     }
 
-    pub fn poll_result_sync(&self) -> Option<DbResult> {
-        if let Ok(mut state) = self.state.try_lock() {
-            state.output_queue.pop_front()
-        } else {
-            None
+    /// Runs `f` against the pool on the worker's runtime and waits for it, so callers
+    /// never need to be inside a runtime themselves. Must not be called from one.
+    fn block_on_pool<R, F, Fut>(&self, f: F) -> Result<R, String>
+    where
+        F: FnOnce(DbPool) -> Fut,
+        Fut: Future<Output = Result<R, String>>,
+    {
+        self.shared.runtime.block_on(f(self.shared.pool.clone()))
+    }
+
+    /// Next finished job, without blocking.
+    pub fn poll_result_sync(&self) -> Option<DbResult<T>> {
+        self.results.lock().unwrap().try_recv().ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rows::{NewUserRow, UserRow};
+
+    fn memory_worker() -> DbWorker<u32> {
+        let config = DbWorkerConfig { connect_attempts: 1, ..DbWorkerConfig::default() };
+        DbWorker::new("sqlite::memory:", config).unwrap()
+    }
+
+    fn next_result(db: &DbWorker<u32>) -> DbResult<u32> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            if let Some(result) = db.poll_result_sync() {
+                return result;
+            }
+            assert!(Instant::now() < deadline, "no DB result within 5s");
+            std::thread::sleep(Duration::from_millis(5));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn sqlite_connections_wait_for_locks_up_to_the_statement_timeout() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let pool = runtime
+            .block_on(DbPool::connect("sqlite::memory:", 1, Duration::from_secs(1), Duration::from_millis(1234)))
+            .unwrap();
+        let DbPool::Sqlite(pool) = pool else {
+            panic!("expected a SQLite pool");
+        };
+        let busy: i64 = runtime.block_on(sqlx::query_scalar("PRAGMA busy_timeout").fetch_one(&pool)).unwrap();
+        assert_eq!(busy, 1234);
+    }
+
+    #[test]
+    fn memory_database_survives_between_sync_calls() {
+        let db = memory_worker();
+        assert_eq!(db.backend(), DbBackend::Sqlite);
+        assert!(!db.migrate().unwrap().is_empty());
+        assert!(db.migration_status().unwrap().is_current());
+        db.prepare_schema(false).unwrap();
+        assert!(db.migrate().unwrap().is_empty());
+    }
+
+    #[test]
+    fn queued_jobs_see_the_migrated_memory_database() {
+        let db = memory_worker();
+        db.prepare_schema(true).unwrap();
+
+        db.queue_job(DbStmt::CreateUser, vec!["alice".into(), "hash".into()], 1).unwrap();
+        let created = next_result(&db);
+        assert!(created.success, "{}", created.error_message());
+        assert_eq!(created.token, 1);
+        let id = created.decode::<NewUserRow>().unwrap()[0].id;
+
+        db.queue_job(DbStmt::CreateUser, vec!["alice".into(), "other".into()], 2).unwrap();
+        let taken = next_result(&db);
+        assert!(taken.success);
+        assert!(taken.decode::<NewUserRow>().unwrap().is_empty());
+
+        db.queue_job(DbStmt::GetUser, vec!["alice".into()], 3).unwrap();
+        let users = next_result(&db).decode::<UserRow>().unwrap();
+        assert_eq!(users.len(), 1);
+        assert_eq!(users[0].id, id);
+        assert_eq!(users[0].password_hash, "hash");
+        assert!(!users[0].banned);
+
+        let rows = db.query_sync(DbStmt::GetUser, vec!["alice".into()]).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert!(db.shutdown(Duration::from_secs(5)));
+    }
+
+    fn create_user(name: &str) -> DbStep {
+        DbStep::new(DbStmt::CreateUser, vec![name.into(), "hash".into()])
+    }
+
+    #[test]
+    fn transaction_commits_every_step() {
+        let db = memory_worker();
+        db.prepare_schema(true).unwrap();
+        let lockout = DbStep::new(DbStmt::SaveLockout, vec!["account:bob".into(), "4000000000".into()]);
+        db.queue_transaction(DbStmt::CreateUser, vec![create_user("bob"), create_user("carol"), lockout], 1).unwrap();
+
+        let result = next_result(&db);
+        assert!(result.success, "{}", result.error_message());
+        assert_eq!(result.stmt, DbStmt::CreateUser);
+        assert_eq!(result.steps.iter().map(Vec::len).collect::<Vec<_>>(), [1, 1, 0]);
+        assert_eq!(db.query_sync(DbStmt::GetUser, vec!["bob".into()]).unwrap().len(), 1);
+        assert_eq!(db.query_sync(DbStmt::GetUser, vec!["carol".into()]).unwrap().len(), 1);
+        assert_eq!(db.query_sync(DbStmt::GetLockouts, Vec::new()).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn step_without_required_rows_rolls_everything_back() {
+        let db = memory_worker();
+        db.prepare_schema(true).unwrap();
+        // The second CreateUser of the same name returns no row
+        let taken = DbStep { require_rows: true, ..create_user("dave") };
+        db.queue_transaction(DbStmt::CreateUser, vec![create_user("erin"), create_user("dave"), taken], 1).unwrap();
+
+        let result = next_result(&db);
+        assert!(!result.success);
+        assert!(result.steps.is_empty());
+        assert!(result.error_message().contains("step 3"), "{}", result.error_message());
+        assert!(db.query_sync(DbStmt::GetUser, vec!["erin".into()]).unwrap().is_empty());
+        assert!(db.query_sync(DbStmt::GetUser, vec!["dave".into()]).unwrap().is_empty());
+    }
+
+    #[test]
+    fn bad_params_are_refused_when_queued() {
+        let db = memory_worker();
+        db.prepare_schema(true).unwrap();
+        let queued = db.queue_job(DbStmt::UpdatePasswordHash, vec!["not a number".into(), "hash".into()], 1);
+        assert!(matches!(queued, Err(QueueError::Invalid)));
+        assert!(db.query_sync(DbStmt::GetUser, Vec::new()).is_err());
+    }
+
+    #[test]
+    fn retries_stop_when_the_budget_is_spent() {
+        let config = DbWorkerConfig {
+            statement_timeout: Duration::from_millis(30),
+            max_retries: 100,
+            retry_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(10),
+            retry_budget: Duration::from_millis(100),
+            ..DbWorkerConfig::default()
+        };
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let started = Instant::now();
+        let mut timeouts = Vec::new();
+        let outcome: Result<(), DbError> = runtime.block_on(DbWorker::<()>::with_retries(&config, true, |timeout| {
+            timeouts.push(timeout);
+            async move {
+                tokio::time::sleep(timeout).await;
+                Err(DbError::Timeout(timeout))
+            }
+        }));
+
+        assert!(matches!(outcome, Err(DbError::Timeout(_))));
+        assert!(started.elapsed() < Duration::from_millis(300), "took {:?}", started.elapsed());
+        assert!(timeouts.len() > 1 && timeouts.len() < 5, "{:?}", timeouts);
+        assert_eq!(timeouts[0], config.statement_timeout);
+        // The last try only gets what was left of the budget
+        assert!(timeouts.iter().all(|t| *t <= config.statement_timeout));
+    }
+
+    #[test]
+    fn failures_that_are_not_transient_are_not_retried() {
+        let config = DbWorkerConfig { retry_backoff: Duration::from_millis(1), ..DbWorkerConfig::default() };
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let mut tries = 0;
+        let outcome: Result<(), DbError> = runtime.block_on(DbWorker::<()>::with_retries(&config, true, |_| {
+            tries += 1;
+            async { Err(DbError::Failed("constraint".into())) }
+        }));
+        assert!(outcome.is_err());
+        assert_eq!(tries, 1);
+    }
+}
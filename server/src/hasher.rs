@@ -1,241 +1,401 @@
-use std::sync::{Arc, Mutex};
-use std::sync::mpsc::{channel, Receiver, Sender, TryRecvError};
+use std::sync::Mutex;
+use std::sync::mpsc::{channel, Receiver};
 use std::thread;
 use std::time::{Duration, Instant};
-use gns::GnsConnection;
-use password_hash::{PasswordHash, PasswordVerifier};
-use argon2::Argon2;
+use password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use rand::rngs::OsRng;
+use anyhow::anyhow;
+
+use crate::worker_pool::{CancelHandle, LaneConfig, PoolConfig, PoolStats, QueueError, WorkerPool};
 
 #[derive(Debug)]
-pub struct VerifyJob {
-    pub id: u64,
+pub struct VerifyJob<T> {
     pub candidate: String,
     pub hash: String,
-    pub respond_to: GnsConnection,
+    pub token: T,
 }
 
 #[derive(Debug)]
-pub struct VerifyResult {
-    pub id: u64,
+pub struct VerifyResult<T> {
     pub ok: bool,
-    pub respond_to: GnsConnection,
+    /// Fresh PHC string when the password was right but the stored hash
+    /// was made with an outdated algorithm, version or params.
+    pub rehash: Option<String>,
+    pub token: T,
 }
 
-struct WorkerThread {
-    pending_count: usize,
-    idle_calls: usize,
-    last_activity: Instant,
+#[derive(Debug)]
+pub struct HashJob<T> {
+    pub password: String,
+    pub token: T,
 }
 
-struct WorkerState {
-    workers: Vec<WorkerThread>,
-    max_pending_per_thread: usize,
-    max_idle_calls: usize,
-    next_worker_id: usize,
+#[derive(Debug)]
+pub struct HashResult<T> {
+    /// PHC string on success.
+    pub hash: Result<String, String>,
+    pub token: T,
 }
 
-pub struct Argon2Worker {
-    input_tx: Sender<VerifyJob>,
-    input_rx: Arc<Mutex<Receiver<VerifyJob>>>,
-    output_tx: Arc<Sender<VerifyResult>>,
-    output_rx: Arc<Mutex<Receiver<VerifyResult>>>,
-    state: Arc<Mutex<WorkerState>>,
+/// `token` in every job is the caller's, it comes back untouched in the result.
+#[derive(Debug)]
+pub enum HasherJob<T> {
+    Verify(VerifyJob<T>),
+    Hash(HashJob<T>),
 }
 
-impl Argon2Worker {
-    pub fn new(parallelism: usize) -> Self {
-        let (input_tx, input_rx) = channel::<VerifyJob>();
-        let (output_tx, output_rx) = channel::<VerifyResult>();
+#[derive(Debug)]
+pub enum HasherResult<T> {
+    Verify(VerifyResult<T>),
+    Hash(HashResult<T>),
+}
 
-        let input_rx = Arc::new(Mutex::new(input_rx));
-        let output_tx = Arc::new(output_tx);
+/// How passwords get hashed and verified.
+#[derive(Debug, Clone)]
+pub struct HasherPolicy {
+    pub algorithm: Algorithm,
+    pub version: Version,
+    pub params: Params,
+    /// Server-side secret mixed into every hash. Ones made with a different pepper no
+    /// longer verify.
+    pub pepper: Option<Vec<u8>>,
+    /// Also try hashes without the pepper, so accounts stored before it was set still log
+    /// in and get rehashed with it. Every wrong password then costs two Argon2 runs, turn
+    /// it off once the old accounts have logged in or had their passwords reset.
+    pub allow_unpeppered_legacy: bool,
+    /// Hashing threads kept alive while idle.
+    pub min_threads: usize,
+    /// Upper bound on hashing threads.
+    pub max_concurrency: usize,
+}
 
-        let state = Arc::new(Mutex::new(WorkerState {
-            workers: Vec::new(),
-            max_pending_per_thread: 5,
-            max_idle_calls: 25,
-            next_worker_id: 0,
-        }));
-
-        // Spawn initial worker threads
-        for i in 0..parallelism {
-            {
-                let mut state_lock = state.lock().unwrap();
-                state_lock.workers.push(WorkerThread {
-                    pending_count: 0,
-                    idle_calls: 0,
-                    last_activity: Instant::now(),
-                });
-            }
-            Self::spawn_worker_thread(
-                state.clone(),
-                input_rx.clone(),
-                output_tx.clone(),
-                i
-            );
+impl Default for HasherPolicy {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::Argon2id,
+            version: Version::V0x13,
+            // m=65536,t=3,p=4, what the existing accounts were created with
+            params: Params::new(65536, 3, 4, None).expect("valid argon2 params"),
+            pepper: None,
+            allow_unpeppered_legacy: false,
+            min_threads: 1,
+            max_concurrency: 4,
+        }
+    }
+}
+
+impl HasherPolicy {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_concurrency == 0 {
+            return Err(anyhow!("argon2 max concurrency must be at least 1"));
         }
+        if self.min_threads > self.max_concurrency {
+            return Err(anyhow!("argon2 min threads must not exceed max concurrency"));
+        }
+        if self.allow_unpeppered_legacy && self.pepper.is_none() {
+            return Err(anyhow!("argon2 allow_unpeppered_legacy only makes sense with a pepper"));
+        }
+        // Also checks the pepper length limits
+        self.argon2()?;
+        Ok(())
+    }
 
-        Self {
-            input_tx,
-            input_rx: input_rx.clone(),
-            output_tx: output_tx.clone(),
-            output_rx: Arc::new(Mutex::new(output_rx)),
-            state,
+    fn argon2(&self) -> anyhow::Result<Argon2<'_>> {
+        match &self.pepper {
+            Some(pepper) => Argon2::new_with_secret(pepper, self.algorithm, self.version, self.params.clone())
+                .map_err(|e| anyhow!("invalid argon2 pepper: {}", e)),
+            None => Ok(Argon2::new(self.algorithm, self.version, self.params.clone())),
         }
     }
 
-    fn spawn_worker_thread(
-        state: Arc<Mutex<WorkerState>>,
-        input_rx: Arc<Mutex<Receiver<VerifyJob>>>,
-        output_tx: Arc<Sender<VerifyResult>>,
-        worker_id: usize,
-    ) {
-        thread::spawn(move || {
-            loop {
-                let job_opt = {
-                    let rx = input_rx.lock().unwrap();
-                    // Use recv_timeout to avoid busy waiting but still check idle status
-                    match rx.recv_timeout(Duration::from_millis(100)) {
-                        Ok(job) => Some(job),
-                        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => None,
-                        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
-                            println!("Argon2 Worker {} shutting down (channel closed)", worker_id);
-                            return;
-                        }
-                    }
-                };
+    /// This policy without the pepper, for hashes stored before it was turned on.
+    fn unpeppered(&self) -> Argon2<'_> {
+        Argon2::new(self.algorithm, self.version, self.params.clone())
+    }
 
-                if let Some(job) = job_opt {
-                    // Update worker stats - got a job
-                    {
-                        let mut state = state.lock().unwrap();
-                        if worker_id < state.workers.len() {
-                            state.workers[worker_id].pending_count += 1;
-                            state.workers[worker_id].last_activity = Instant::now();
-                            state.workers[worker_id].idle_calls = 0;
-                        }
-                    }
-
-                    // Do the actual password verification (CPU intensive)
-                    let result = Self::verify_password(job);
-
-                    // Send result back
-                    let _ = output_tx.send(result);
-
-                    // Update worker stats - job done
-                    {
-                        let mut state = state.lock().unwrap();
-                        if worker_id < state.workers.len() {
-                            state.workers[worker_id].pending_count =
-                                state.workers[worker_id].pending_count.saturating_sub(1);
-                        }
-                    }
-                } else {
-                    // Timeout - no job available, check if should terminate
-                    let should_terminate = {
-                        let mut state = state.lock().unwrap();
-                        if worker_id < state.workers.len() {
-                            state.workers[worker_id].idle_calls += 1;
-
-                            if state.workers[worker_id].idle_calls >= state.max_idle_calls
-                                && state.workers.len() > 1 {
-                                state.workers.remove(worker_id);
-                                true
-                            } else {
-                                false
-                            }
-                        } else {
-                            false
-                        }
-                    };
-
-                    if should_terminate {
-                        println!("Argon2 Worker {} terminated due to inactivity", worker_id);
-                        return;
-                    }
-                }
+    /// Hashes with a fresh salt on the calling thread.
+    pub fn make_hash(&self, password: &str) -> Result<String, String> {
+        let salt = SaltString::generate(&mut OsRng);
+        self.argon2()
+            .map_err(|e| e.to_string())?
+            .hash_password(password.as_bytes(), &salt)
+            .map(|phc| phc.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    /// True when `parsed` was not made with this policy and should be replaced.
+    pub fn needs_rehash(&self, parsed: &PasswordHash) -> bool {
+        if parsed.algorithm != self.algorithm.ident() {
+            return true;
+        }
+        if parsed.version.unwrap_or(Version::V0x10 as u32) != self.version as u32 {
+            return true;
+        }
+        match Params::try_from(parsed) {
+            Ok(params) => {
+                params.m_cost() != self.params.m_cost()
+                    || params.t_cost() != self.params.t_cost()
+                    || params.p_cost() != self.params.p_cost()
+                    // A parsed hash always knows its length, a policy built with None means the default
+                    || params.output_len().unwrap_or(Params::DEFAULT_OUTPUT_LEN)
+                        != self.params.output_len().unwrap_or(Params::DEFAULT_OUTPUT_LEN)
             }
+            Err(_) => true,
+        }
+    }
+}
+
+pub struct Argon2Worker<T: Send + 'static> {
+    /// Hash of a random password made with the current policy, verified against
+    /// for unknown users so they cost the same as real ones. Misses against cheaper
+    /// hashes (legacy params, unparseable) are padded to what a miss on it costs.
+    decoy_hash: String,
+    workers: WorkerPool<HasherJob<T>>,
+    output_rx: Mutex<Receiver<HasherResult<T>>>,
+}
+
+impl<T: Send + 'static> Argon2Worker<T> {
+    /// Starts `policy.min_threads` worker threads, more get spawned on demand up to
+    /// `policy.max_concurrency`.
+    pub fn new(policy: HasherPolicy) -> Self {
+        let decoy_password = SaltString::generate(&mut OsRng);
+        let decoy_hash = policy.make_hash(decoy_password.as_str())
+            .expect("argon2 policy should be validated before starting the worker");
+        // Goes through the same checks as a wrong password, unpeppered retry included
+        let miss_floor = {
+            let started = Instant::now();
+            Self::check_password(&policy, "", &decoy_hash);
+            started.elapsed()
+        };
+
+        let (output_tx, output_rx) = channel::<HasherResult<T>>();
+        let config = PoolConfig {
+            name: "Argon2",
+            min_threads: policy.min_threads,
+            max_threads: policy.max_concurrency,
+            max_pending_per_thread: 5,
+            idle_timeout: Duration::from_millis(2500),
+            lanes: vec![LaneConfig { name: "default", weight: 1, queue_capacity: 1024 }],
+        };
+
+        let workers = WorkerPool::new(config, move |_| {
+            let policy = policy.clone();
+            let output_tx = output_tx.clone();
+            Box::new(move |job| {
+                // CPU bound, this is the whole reason for the pool
+                let result = match job {
+                    HasherJob::Verify(job) => HasherResult::Verify(Self::verify_password(&policy, miss_floor, job)),
+                    HasherJob::Hash(job) => HasherResult::Hash(Self::hash_password(&policy, job)),
+                };
+                let _ = output_tx.send(result);
+            })
         });
+
+        Self {
+            decoy_hash,
+            workers,
+            output_rx: Mutex::new(output_rx),
+        }
     }
 
-    fn verify_password(job: VerifyJob) -> VerifyResult {
-        let VerifyJob { id, candidate, hash, respond_to } = job;
+    /// Misses take at least `miss_floor`, so an account with an old hash does not
+    /// answer faster than an unknown user verified against the decoy.
+    fn verify_password(policy: &HasherPolicy, miss_floor: Duration, job: VerifyJob<T>) -> VerifyResult<T> {
+        let VerifyJob { candidate, hash, token } = job;
+        let started = Instant::now();
+        let (ok, rehash) = Self::check_password(policy, &candidate, &hash);
+        if !ok && let Some(left) = miss_floor.checked_sub(started.elapsed()) {
+            thread::sleep(left);
+        }
+        VerifyResult { ok, rehash, token }
+    }
 
-        let ok = match PasswordHash::new(&hash) {
+    fn check_password(policy: &HasherPolicy, candidate: &str, hash: &str) -> (bool, Option<String>) {
+        match PasswordHash::new(hash) {
             Ok(parsed) => {
-                let argon2 = Argon2::default();
-                argon2.verify_password(candidate.as_bytes(), &parsed).is_ok()
+                let peppered = match policy.argon2() {
+                    Ok(argon2) => argon2.verify_password(candidate.as_bytes(), &parsed).is_ok(),
+                    Err(_) => false,
+                };
+                // The PHC string does not say whether a pepper was used, so a hash from before
+                // the pepper was turned on only shows up as a failed peppered check
+                let unpeppered = !peppered
+                    && policy.allow_unpeppered_legacy
+                    && policy.unpeppered().verify_password(candidate.as_bytes(), &parsed).is_ok();
+                let ok = peppered || unpeppered;
+                // Only a verified password may be rehashed, and only when the policy moved on
+                let rehash = if ok && (unpeppered || policy.needs_rehash(&parsed)) {
+                    policy.make_hash(candidate).ok()
+                } else {
+                    None
+                };
+                (ok, rehash)
             }
-            Err(_) => false,
-        };
+            Err(_) => (false, None),
+        }
+    }
 
-        VerifyResult { id, ok, respond_to }
+    fn hash_password(policy: &HasherPolicy, job: HashJob<T>) -> HashResult<T> {
+        let HashJob { password, token } = job;
+        let hash = policy.make_hash(&password);
+        HashResult { hash, token }
     }
 
-    pub fn queue_job(&self, candidate: impl Into<String>, hash: impl Into<String>, id: u64, respond_to: GnsConnection) -> bool {
+    pub fn queue_job(&self, candidate: impl Into<String>, hash: impl Into<String>, token: T) -> Result<CancelHandle, QueueError> {
         let job = VerifyJob {
-            id,
             candidate: candidate.into(),
             hash: hash.into(),
-            respond_to,
+            token,
         };
+        self.queue(HasherJob::Verify(job))
+    }
 
-        // Check if we need to spawn a new worker based on pending count
-        if let Ok(mut state) = self.state.try_lock() {
-            // Find worker with least pending jobs
-            let min_pending_worker = state.workers.iter()
-                .enumerate()
-                .min_by_key(|(_, w)| w.pending_count)
-                .map(|(idx, w)| (idx, w.pending_count));
-
-            // Check if we need to spawn a new worker
-            if let Some((_, min_pending)) = min_pending_worker {
-                if min_pending >= state.max_pending_per_thread {
-                    // Spawn new worker
-                    let new_id = state.next_worker_id;
-                    state.next_worker_id += 1;
-
-                    state.workers.push(WorkerThread {
-                        pending_count: 0,
-                        idle_calls: 0,
-                        last_activity: Instant::now(),
-                    });
-
-                    println!("Spawning new Argon2 worker thread {}", new_id);
-                    Self::spawn_worker_thread(
-                        self.state.clone(),
-                        self.input_rx.clone(),
-                        self.output_tx.clone(),
-                        new_id
-                    );
-                }
-            }
+    /// Queues a verification that always fails but takes as long as a real one.
+    pub fn queue_decoy_job(&self, candidate: impl Into<String>, token: T) -> Result<CancelHandle, QueueError> {
+        self.queue_job(candidate, self.decoy_hash.clone(), token)
+    }
 
-            // Reset idle calls for all workers when there's work
-            for worker in &mut state.workers {
-                worker.idle_calls = 0;
-            }
+    /// Queues hashing a new password with a fresh salt, result comes back as HasherResult::Hash.
+    pub fn queue_hash_job(&self, password: impl Into<String>, token: T) -> Result<CancelHandle, QueueError> {
+        let job = HashJob {
+            password: password.into(),
+            token,
+        };
+        self.queue(HasherJob::Hash(job))
+    }
 
-            // Drop the lock before sending to avoid holding it during channel send
-            drop(state);
-        } else {
-            // Could not acquire lock, but we can still try to queue the job
-            // since the channel send itself is independent of the state lock
+    fn queue(&self, job: HasherJob<T>) -> Result<CancelHandle, QueueError> {
+        self.workers.submit(job)
+    }
+
+    /// Stops taking jobs, lets the workers finish what is already queued and joins them.
+    /// Returns false if they didn't all exit within `timeout`.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        self.workers.shutdown(timeout)
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.workers.stats()
+    }
+
+    pub fn poll_result_sync(&self) -> Option<HasherResult<T>> {
+        self.output_rx.try_lock().ok().and_then(|rx| rx.try_recv().ok())
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(pepper: Option<&str>) -> HasherPolicy {
+        HasherPolicy {
+            // Cheap params, the tests are about which hashes verify
+            params: Params::new(256, 1, 1, None).unwrap(),
+            pepper: pepper.map(|p| p.as_bytes().to_vec()),
+            ..HasherPolicy::default()
         }
+    }
 
-        // Send job to workers (non-blocking from caller's perspective)
-        // Return true if successfully sent, false if channel is full/disconnected
-        self.input_tx.send(job).is_ok()
+    fn legacy(pepper: &str) -> HasherPolicy {
+        HasherPolicy { allow_unpeppered_legacy: true, ..policy(Some(pepper)) }
     }
 
-    pub fn poll_result_sync(&self) -> Option<VerifyResult> {
-        if let Ok(rx) = self.output_rx.try_lock() {
-            match rx.try_recv() {
-                Ok(result) => Some(result),
-                Err(_) => None,
+    fn verify(policy: &HasherPolicy, candidate: &str, hash: &str) -> VerifyResult<()> {
+        let job = VerifyJob { candidate: candidate.to_string(), hash: hash.to_string(), token: () };
+        Argon2Worker::<()>::verify_password(policy, Duration::ZERO, job)
+    }
+
+    fn next_verify(worker: &Argon2Worker<u32>) -> VerifyResult<u32> {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        loop {
+            match worker.poll_result_sync() {
+                Some(HasherResult::Verify(result)) => return result,
+                Some(HasherResult::Hash(_)) => panic!("expected a verify result"),
+                None if Instant::now() < deadline => thread::sleep(Duration::from_millis(5)),
+                None => panic!("no verify result in time"),
             }
-        } else {
-            None
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn current_hash_verifies_without_rehash() {
+        let policy = policy(Some("pepper"));
+        let hash = policy.make_hash("hunter22").unwrap();
+        let result = verify(&policy, "hunter22", &hash);
+        assert!(result.ok);
+        assert!(result.rehash.is_none());
+        assert!(!verify(&policy, "hunter23", &hash).ok);
+    }
+
+    #[test]
+    fn unpeppered_hash_verifies_and_gets_rehashed_with_the_pepper() {
+        let hash = policy(None).make_hash("hunter22").unwrap();
+        let peppered = legacy("pepper");
+
+        let result = verify(&peppered, "hunter22", &hash);
+        assert!(result.ok);
+        let rehash = result.rehash.expect("unpeppered hash should be replaced");
+        // The replacement needs the pepper
+        assert!(!verify(&policy(None), "hunter22", &rehash).ok);
+        let again = verify(&peppered, "hunter22", &rehash);
+        assert!(again.ok);
+        assert!(again.rehash.is_none());
+
+        assert!(!verify(&peppered, "hunter23", &hash).ok);
+    }
+
+    #[test]
+    fn unpeppered_hash_is_refused_once_legacy_is_off() {
+        let hash = policy(None).make_hash("hunter22").unwrap();
+        let result = verify(&policy(Some("pepper")), "hunter22", &hash);
+        assert!(!result.ok);
+        assert!(result.rehash.is_none());
+        assert!(HasherPolicy { allow_unpeppered_legacy: true, ..policy(None) }.validate().is_err());
+    }
+
+    #[test]
+    fn hash_with_another_pepper_does_not_verify() {
+        let hash = policy(Some("old")).make_hash("hunter22").unwrap();
+        let result = verify(&policy(Some("new")), "hunter22", &hash);
+        assert!(!result.ok);
+        assert!(result.rehash.is_none());
+    }
+
+    #[test]
+    fn decoy_fails_like_a_wrong_password() {
+        let policy = legacy("pepper");
+        let hash = policy.make_hash("hunter22").unwrap();
+        let worker = Argon2Worker::<u32>::new(policy);
+
+        worker.queue_job("hunter23", hash, 1).unwrap();
+        let miss = next_verify(&worker);
+        worker.queue_decoy_job("hunter22", 2).unwrap();
+        let decoy = next_verify(&worker);
+
+        assert_eq!((miss.ok, miss.rehash, miss.token), (false, None, 1));
+        assert_eq!((decoy.ok, decoy.rehash, decoy.token), (false, None, 2));
+        assert!(worker.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn misses_against_cheaper_hashes_are_padded() {
+        let floor = Duration::from_millis(50);
+        let cheap = HasherPolicy { params: Params::new(64, 1, 1, None).unwrap(), ..policy(None) };
+        let hash = cheap.make_hash("hunter22").unwrap();
+        for hash in [hash.as_str(), "not a phc string"] {
+            let job = VerifyJob { candidate: "hunter23".to_string(), hash: hash.to_string(), token: () };
+            let started = Instant::now();
+            assert!(!Argon2Worker::<()>::verify_password(&policy(None), floor, job).ok);
+            assert!(started.elapsed() >= floor);
+        }
+    }
+
+    #[test]
+    fn outdated_params_get_rehashed() {
+        let old = HasherPolicy { params: Params::new(128, 1, 1, None).unwrap(), ..policy(None) };
+        let hash = old.make_hash("hunter22").unwrap();
+        let result = verify(&policy(None), "hunter22", &hash);
+        assert!(result.ok);
+        assert!(result.rehash.is_some());
+    }
+}
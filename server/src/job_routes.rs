@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+
+use crate::worker_pool::CancelHandle;
+
+struct Route<K> {
+    owner: K,
+    cancel: Option<CancelHandle>,
+}
+
+/// Correlation tokens for work handed to the DB and Argon2 workers. The workers only
+/// ever see the token, this maps it back to the connection the result belongs to.
+pub struct JobRoutes<K> {
+    next_token: u64,
+    routes: HashMap<u64, Route<K>>,
+    /// Jobs dropped by forget. Only the ones still queued are actually saved, the pools
+    /// count those as cancelled, results of the others come back and are discarded.
+    forgotten: u64,
+    /// Results that came back for a forgotten owner and were thrown away.
+    discarded: u64,
+}
+
+impl<K: Copy + Eq + Hash> JobRoutes<K> {
+    /// Token for fire and forget jobs whose result nobody waits for.
+    pub const UNROUTED: u64 = 0;
+
+    pub fn new() -> Self {
+        Self {
+            next_token: Self::UNROUTED + 1,
+            routes: HashMap::new(),
+            forgotten: 0,
+            discarded: 0,
+        }
+    }
+
+    /// A fresh token for a job run on behalf of `owner`.
+    pub fn issue(&mut self, owner: K) -> u64 {
+        let token = self.next_token;
+        self.next_token += 1;
+        self.routes.insert(token, Route { owner, cancel: None });
+        token
+    }
+
+    /// Remembers how to cancel the job queued under `token`, see forget.
+    pub fn attach(&mut self, token: u64, cancel: CancelHandle) {
+        if let Some(route) = self.routes.get_mut(&token) {
+            route.cancel = Some(cancel);
+        }
+    }
+
+    /// Who a finished job was for. None for unrouted jobs and for owners that were
+    /// forgotten in the meantime, their results are stale.
+    pub fn take(&mut self, token: u64) -> Option<K> {
+        let owner = self.routes.remove(&token).map(|route| route.owner);
+        if owner.is_none() && token != Self::UNROUTED {
+            self.discarded += 1;
+        }
+        owner
+    }
+
+    /// Drops the token of a job that never got queued, without counting it anywhere.
+    pub fn release(&mut self, token: u64) {
+        self.routes.remove(&token);
+    }
+
+    /// Cancels and drops every job of `owner`, for a connection that went away.
+    /// Queued ones never run, results of ones already running get discarded by take.
+    pub fn forget(&mut self, owner: &K) -> usize {
+        let before = self.routes.len();
+        self.routes.retain(|_, route| {
+            if route.owner != *owner {
+                return true;
+            }
+            if let Some(cancel) = &route.cancel {
+                cancel.cancel();
+            }
+            false
+        });
+        let forgotten = before - self.routes.len();
+        self.forgotten += forgotten as u64;
+        forgotten
+    }
+
+    /// Jobs still waiting for a result.
+    pub fn len(&self) -> usize {
+        self.routes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.routes.is_empty()
+    }
+
+    pub fn forgotten(&self) -> u64 {
+        self.forgotten
+    }
+
+    pub fn discarded(&self) -> u64 {
+        self.discarded
+    }
+}
+
+impl<K: Copy + Eq + Hash> Default for JobRoutes<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_go_back_to_their_owner_once() {
+        let mut routes = JobRoutes::new();
+        let a = routes.issue('a');
+        let b = routes.issue('b');
+        assert_ne!(a, JobRoutes::<char>::UNROUTED);
+        assert_ne!(a, b);
+        assert_eq!(routes.len(), 2);
+
+        assert_eq!(routes.take(b), Some('b'));
+        assert_eq!(routes.take(a), Some('a'));
+        assert!(routes.is_empty());
+        // A second result for the same token is stale
+        assert_eq!(routes.take(a), None);
+        assert_eq!(routes.discarded(), 1);
+    }
+
+    #[test]
+    fn unrouted_and_released_tokens_are_not_counted() {
+        let mut routes = JobRoutes::new();
+        assert_eq!(routes.take(JobRoutes::<char>::UNROUTED), None);
+        let token = routes.issue('a');
+        routes.release(token);
+        assert!(routes.is_empty());
+        assert_eq!((routes.forgotten(), routes.discarded()), (0, 0));
+    }
+
+    #[test]
+    fn forget_cancels_only_that_owners_jobs() {
+        let mut routes = JobRoutes::new();
+        let queued = CancelHandle::default();
+        let other = CancelHandle::default();
+        let a = routes.issue('a');
+        routes.attach(a, queued.clone());
+        let running = routes.issue('a');
+        let b = routes.issue('b');
+        routes.attach(b, other.clone());
+
+        assert_eq!(routes.forget(&'a'), 2);
+        assert!(queued.is_cancelled());
+        assert!(!other.is_cancelled());
+        assert_eq!(routes.len(), 1);
+
+        // The job that was already running still reports back, to nobody
+        assert_eq!(routes.take(running), None);
+        assert_eq!(routes.take(b), Some('b'));
+        assert_eq!((routes.forgotten(), routes.discarded()), (2, 1));
+    }
+}
@@ -0,0 +1,322 @@
+use std::fmt;
+use sha2::{Digest, Sha256};
+use sqlx::{Connection, Executor, Row as SqlxRow};
+
+use crate::db::{DbBackend, DbPool};
+
+/// A schema change shipped inside the binary, written once per dialect. Never edit one
+/// that has been released, add a new file instead, the checksum check will refuse to
+/// start otherwise.
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub postgres: &'static str,
+    pub sqlite: &'static str,
+}
+
+impl Migration {
+    pub fn sql(&self, backend: DbBackend) -> &'static str {
+        match backend {
+            DbBackend::Postgres => self.postgres,
+            DbBackend::Sqlite => self.sqlite,
+        }
+    }
+
+    pub fn checksum(&self, backend: DbBackend) -> String {
+        Sha256::digest(self.sql(backend).as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+macro_rules! migration {
+    ($version:expr, $name:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            postgres: include_str!(concat!("../migrations/postgres/", $name, ".sql")),
+            sqlite: include_str!(concat!("../migrations/sqlite/", $name, ".sql")),
+        }
+    };
+}
+
+/// Every migration in version order.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(1, "0001_users"),
+    migration!(2, "0002_login_lockouts"),
+    migration!(3, "0003_characters"),
+    migration!(4, "0004_bans"),
+];
+
+// Any constant works, it only has to be the same for every server on the database
+const MIGRATION_LOCK_ID: i64 = 0x4156_4552_4e4f; // "AVERNO"
+
+// Valid in both dialects
+const CREATE_TRACKING_TABLE: &str = r#"CREATE TABLE IF NOT EXISTS "SCHEMA_MIGRATION" (
+    version INTEGER PRIMARY KEY,
+    name TEXT NOT NULL,
+    checksum TEXT NOT NULL,
+    appliedAt TIMESTAMPTZ NOT NULL DEFAULT CURRENT_TIMESTAMP
+)"#;
+
+const SELECT_APPLIED: &str = r#"SELECT version, name, checksum FROM "SCHEMA_MIGRATION" ORDER BY version"#;
+
+/// The database and this build disagree about migrations that already ran.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaDrift {
+    /// Applied on the database but unknown here, the database was migrated by a newer build.
+    Unknown { version: i32, name: String },
+    /// The file changed after it was applied.
+    Modified { version: i32, name: String },
+}
+
+impl fmt::Display for SchemaDrift {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SchemaDrift::Unknown { version, name } => {
+                write!(f, "migration {} ({}) is applied but unknown to this build", version, name)
+            }
+            SchemaDrift::Modified { version, name } => {
+                write!(f, "migration {} ({}) was modified after it was applied", version, name)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MigrationStatus {
+    pub applied: Vec<i32>,
+    pub pending: Vec<i32>,
+    pub drift: Vec<SchemaDrift>,
+}
+
+impl MigrationStatus {
+    pub fn is_current(&self) -> bool {
+        self.pending.is_empty() && self.drift.is_empty()
+    }
+}
+
+// The same steps for both backends, only the connection type and the placeholders differ.
+macro_rules! backend_steps {
+    ($module:ident, $conn:ty, $backend:expr, $insert:literal) => {
+        mod $module {
+            use super::*;
+
+            pub async fn read_applied(conn: &mut $conn) -> Result<Vec<(i32, String, String)>, String> {
+                (&mut *conn).execute(CREATE_TRACKING_TABLE).await.map_err(|e| e.to_string())?;
+                let rows = sqlx::query(SELECT_APPLIED)
+                    .fetch_all(&mut *conn)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                rows.iter()
+                    .map(|row| {
+                        Ok((
+                            row.try_get::<i32, _>(0).map_err(|e| e.to_string())?,
+                            row.try_get::<String, _>(1).map_err(|e| e.to_string())?,
+                            row.try_get::<String, _>(2).map_err(|e| e.to_string())?,
+                        ))
+                    })
+                    .collect()
+            }
+
+            pub async fn apply_pending(conn: &mut $conn) -> Result<Vec<i32>, String> {
+                let status = compare(&read_applied(conn).await?, $backend);
+                if let Some(drift) = status.drift.first() {
+                    return Err(format!("schema drift: {}", drift));
+                }
+
+                let mut done = Vec::new();
+                for migration in MIGRATIONS.iter().filter(|m| status.pending.contains(&m.version)) {
+                    let mut tx = conn.begin().await.map_err(|e| e.to_string())?;
+                    // Executed as a simple query, so a file may hold several statements
+                    (&mut *tx).execute(migration.sql($backend))
+                        .await
+                        .map_err(|e| format!("migration {} ({}) failed: {}", migration.version, migration.name, e))?;
+                    sqlx::query($insert)
+                        .bind(migration.version)
+                        .bind(migration.name)
+                        .bind(migration.checksum($backend))
+                        .execute(&mut *tx)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    tx.commit().await.map_err(|e| e.to_string())?;
+                    log::info!("Applied migration {} ({})", migration.version, migration.name);
+                    done.push(migration.version);
+                }
+                Ok(done)
+            }
+        }
+    };
+}
+
+backend_steps!(
+    pg,
+    sqlx::PgConnection,
+    DbBackend::Postgres,
+    r#"INSERT INTO "SCHEMA_MIGRATION" (version, name, checksum) VALUES ($1, $2, $3)"#
+);
+backend_steps!(
+    lite,
+    sqlx::SqliteConnection,
+    DbBackend::Sqlite,
+    r#"INSERT INTO "SCHEMA_MIGRATION" (version, name, checksum) VALUES (?1, ?2, ?3)"#
+);
+
+fn compare(applied: &[(i32, String, String)], backend: DbBackend) -> MigrationStatus {
+    let mut status = MigrationStatus::default();
+    for (version, name, checksum) in applied {
+        match MIGRATIONS.iter().find(|m| m.version == *version) {
+            None => status.drift.push(SchemaDrift::Unknown { version: *version, name: name.clone() }),
+            Some(m) if m.checksum(backend) != *checksum => {
+                status.drift.push(SchemaDrift::Modified { version: *version, name: name.clone() })
+            }
+            Some(_) => status.applied.push(*version),
+        }
+    }
+    status.pending = MIGRATIONS
+        .iter()
+        .map(|m| m.version)
+        .filter(|v| !applied.iter().any(|(applied, _, _)| applied == v))
+        .collect();
+    status
+}
+
+/// Reports applied, pending and drifted migrations without changing anything
+/// besides creating the tracking table.
+pub async fn status(pool: &DbPool) -> Result<MigrationStatus, String> {
+    let applied = match pool {
+        DbPool::Postgres(pool) => {
+            let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+            pg::read_applied(&mut conn).await?
+        }
+        DbPool::Sqlite(pool) => {
+            let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+            lite::read_applied(&mut conn).await?
+        }
+    };
+    Ok(compare(&applied, pool.backend()))
+}
+
+/// Applies every pending migration, each in its own transaction. Refuses to touch a
+/// database that drifted. Returns the versions that were applied.
+pub async fn apply(pool: &DbPool) -> Result<Vec<i32>, String> {
+    match pool {
+        DbPool::Postgres(pool) => {
+            let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+            // Waiting for the lock and building indexes may take longer than a game query
+            (&mut *conn).execute("SET statement_timeout = 0").await.map_err(|e| e.to_string())?;
+            // Two servers starting at once must not both run the same migration
+            sqlx::query("SELECT pg_advisory_lock($1)")
+                .bind(MIGRATION_LOCK_ID)
+                .execute(&mut *conn)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            let result = pg::apply_pending(&mut conn).await;
+
+            if let Err(e) = sqlx::query("SELECT pg_advisory_unlock($1)")
+                .bind(MIGRATION_LOCK_ID)
+                .execute(&mut *conn)
+                .await
+            {
+                log::warn!("Could not release the migration lock: {}", e);
+            }
+            // Back to the timeout the pool set on connect before the connection is reused
+            if let Err(e) = (&mut *conn).execute("RESET statement_timeout").await {
+                log::warn!("Could not reset the statement timeout, closing the connection: {}", e);
+                drop(conn.detach());
+            }
+            result
+        }
+        // SQLite only allows one writer anyway
+        DbPool::Sqlite(pool) => {
+            let mut conn = pool.acquire().await.map_err(|e| e.to_string())?;
+            lite::apply_pending(&mut conn).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn applied(version: i32, checksum: String) -> (i32, String, String) {
+        (version, format!("v{}", version), checksum)
+    }
+
+    fn memory_pool(runtime: &tokio::runtime::Runtime) -> DbPool {
+        runtime
+            .block_on(DbPool::connect("sqlite::memory:", 1, Duration::from_secs(1), Duration::from_secs(1)))
+            .unwrap()
+    }
+
+    #[test]
+    fn versions_are_unique_and_ascending() {
+        assert!(MIGRATIONS.windows(2).all(|pair| pair[0].version < pair[1].version));
+        assert_eq!(MIGRATIONS[0].version, 1);
+        for migration in MIGRATIONS {
+            assert_ne!(migration.checksum(DbBackend::Postgres), migration.checksum(DbBackend::Sqlite));
+        }
+    }
+
+    #[test]
+    fn compare_sorts_applied_pending_and_drifted() {
+        let backend = DbBackend::Sqlite;
+        let status = compare(&[], backend);
+        assert_eq!(status.pending.len(), MIGRATIONS.len());
+        assert!(!status.is_current());
+
+        let first = &MIGRATIONS[0];
+        let status = compare(
+            &[
+                applied(first.version, first.checksum(backend)),
+                applied(2, "edited".into()),
+                applied(999, "newer".into()),
+            ],
+            backend,
+        );
+        assert_eq!(status.applied, [1]);
+        assert_eq!(status.pending, MIGRATIONS.iter().map(|m| m.version).filter(|v| *v > 2).collect::<Vec<_>>());
+        assert_eq!(
+            status.drift,
+            [
+                SchemaDrift::Modified { version: 2, name: "v2".into() },
+                SchemaDrift::Unknown { version: 999, name: "v999".into() },
+            ]
+        );
+    }
+
+    #[test]
+    fn sqlite_applies_every_migration_once() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let pool = memory_pool(&runtime);
+        assert!(runtime.block_on(status(&pool)).unwrap().applied.is_empty());
+
+        let done = runtime.block_on(apply(&pool)).unwrap();
+        assert_eq!(done, MIGRATIONS.iter().map(|m| m.version).collect::<Vec<_>>());
+        assert!(runtime.block_on(apply(&pool)).unwrap().is_empty());
+        let current = runtime.block_on(status(&pool)).unwrap();
+        assert!(current.is_current());
+        assert_eq!(current.applied, done);
+    }
+
+    #[test]
+    fn drifted_database_is_left_alone() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let pool = memory_pool(&runtime);
+        runtime.block_on(apply(&pool)).unwrap();
+        let DbPool::Sqlite(sqlite) = &pool else {
+            unreachable!()
+        };
+        runtime
+            .block_on(sqlx::query(r#"UPDATE "SCHEMA_MIGRATION" SET checksum = 'edited' WHERE version = 1"#).execute(sqlite))
+            .unwrap();
+
+        let status = runtime.block_on(status(&pool)).unwrap();
+        assert!(matches!(status.drift[..], [SchemaDrift::Modified { version: 1, .. }]));
+        let refused = runtime.block_on(apply(&pool)).unwrap_err();
+        assert!(refused.contains("schema drift"), "{}", refused);
+    }
+}
@@ -0,0 +1,318 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use sqlx::postgres::PgRow;
+use sqlx::sqlite::SqliteRow;
+use sqlx::types::chrono::{DateTime, NaiveDateTime, Utc};
+use sqlx::types::Uuid;
+use sqlx::{Column, Row as SqlxRow, TypeInfo, ValueRef};
+
+use crate::throttle::{Lockout, ThrottleKey};
+
+/// One column value, decoded from whatever type the database reported.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DbValue {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    Bytes(Vec<u8>),
+    Uuid(Uuid),
+    Timestamp(DateTime<Utc>),
+}
+
+/// A result row with its column names. Postgres folds unquoted names to lower case,
+/// so lookups by name ignore case.
+#[derive(Debug, Clone)]
+pub struct DbRow {
+    columns: Arc<[String]>,
+    values: Vec<DbValue>,
+}
+
+impl DbRow {
+    pub fn new(columns: Arc<[String]>, values: Vec<DbValue>) -> Self {
+        Self { columns, values }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+
+    pub fn value(&self, index: usize) -> Option<&DbValue> {
+        self.values.get(index)
+    }
+
+    pub fn get(&self, column: &str) -> Option<&DbValue> {
+        self.columns
+            .iter()
+            .position(|c| c.eq_ignore_ascii_case(column))
+            .and_then(|i| self.values.get(i))
+    }
+
+    /// Reads `column` as `T`, with an error naming the column when it is missing or
+    /// has another type.
+    pub fn try_get<T: FromDbValue>(&self, column: &str) -> Result<T, String> {
+        let value = self.get(column).ok_or_else(|| format!("missing column {:?}", column))?;
+        T::from_value(value).ok_or_else(|| format!("column {:?} can not be read from {:?}", column, value))
+    }
+}
+
+/// Conversion from a single column value.
+pub trait FromDbValue: Sized {
+    fn from_value(value: &DbValue) -> Option<Self>;
+}
+
+impl FromDbValue for i64 {
+    fn from_value(value: &DbValue) -> Option<Self> {
+        match value {
+            DbValue::Int(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+impl FromDbValue for u32 {
+    fn from_value(value: &DbValue) -> Option<Self> {
+        i64::from_value(value).and_then(|v| u32::try_from(v).ok())
+    }
+}
+
+impl FromDbValue for bool {
+    fn from_value(value: &DbValue) -> Option<Self> {
+        match value {
+            DbValue::Bool(v) => Some(*v),
+            // SQLite has no boolean type
+            DbValue::Int(v) => Some(*v != 0),
+            _ => None,
+        }
+    }
+}
+
+impl FromDbValue for f64 {
+    fn from_value(value: &DbValue) -> Option<Self> {
+        match value {
+            DbValue::Float(v) => Some(*v),
+            DbValue::Int(v) => Some(*v as f64),
+            _ => None,
+        }
+    }
+}
+
+impl FromDbValue for String {
+    fn from_value(value: &DbValue) -> Option<Self> {
+        match value {
+            DbValue::Text(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromDbValue for Uuid {
+    fn from_value(value: &DbValue) -> Option<Self> {
+        match value {
+            DbValue::Uuid(v) => Some(*v),
+            DbValue::Text(v) => v.parse().ok(),
+            _ => None,
+        }
+    }
+}
+
+impl FromDbValue for SystemTime {
+    fn from_value(value: &DbValue) -> Option<Self> {
+        match value {
+            DbValue::Timestamp(v) => {
+                let secs = u64::try_from(v.timestamp()).ok()?;
+                Some(UNIX_EPOCH + Duration::from_secs(secs) + Duration::from_nanos(v.timestamp_subsec_nanos().into()))
+            }
+            // SQLite tables store times as unix seconds
+            DbValue::Int(secs) => u64::try_from(*secs).ok().map(|s| UNIX_EPOCH + Duration::from_secs(s)),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromDbValue> FromDbValue for Option<T> {
+    fn from_value(value: &DbValue) -> Option<Self> {
+        match value {
+            DbValue::Null => Some(None),
+            other => T::from_value(other).map(Some),
+        }
+    }
+}
+
+/// A struct one statement's rows decode into, see DbResult::decode.
+pub trait FromDbRow: Sized {
+    fn from_row(row: &DbRow) -> Result<Self, String>;
+}
+
+/// GetUser
+#[derive(Debug, Clone)]
+pub struct UserRow {
+    pub id: u32,
+    pub password_hash: String,
+    pub banned: bool,
+}
+
+impl FromDbRow for UserRow {
+    fn from_row(row: &DbRow) -> Result<Self, String> {
+        Ok(Self {
+            id: row.try_get("id")?,
+            password_hash: row.try_get("passwordHash")?,
+            banned: row.try_get("banned")?,
+        })
+    }
+}
+
+/// CreateUser, no row when the name was taken.
+#[derive(Debug, Clone)]
+pub struct NewUserRow {
+    pub id: u32,
+}
+
+impl FromDbRow for NewUserRow {
+    fn from_row(row: &DbRow) -> Result<Self, String> {
+        Ok(Self { id: row.try_get("id")? })
+    }
+}
+
+/// GetLockouts
+impl FromDbRow for Lockout {
+    fn from_row(row: &DbRow) -> Result<Self, String> {
+        let key: String = row.try_get("lockKey")?;
+        Ok(Lockout {
+            key: ThrottleKey::from_db_key(&key).ok_or_else(|| format!("invalid lock key {:?}", key))?,
+            until: row.try_get("lockedUntil")?,
+        })
+    }
+}
+
+fn column_names<R: SqlxRow>(row: &R) -> Arc<[String]> {
+    row.columns().iter().map(|c| c.name().to_string()).collect()
+}
+
+fn decode_error(column: &str, e: impl std::fmt::Display) -> String {
+    format!("could not decode column {:?}: {}", column, e)
+}
+
+/// Decodes a Postgres row. Types without a DbValue variant are an error, not a silent NULL.
+pub fn decode_pg_row(row: &PgRow, columns: &Arc<[String]>) -> Result<DbRow, String> {
+    let mut values = Vec::with_capacity(row.len());
+    for (i, name) in columns.iter().enumerate() {
+        let raw = row.try_get_raw(i).map_err(|e| decode_error(name, e))?;
+        if raw.is_null() {
+            values.push(DbValue::Null);
+            continue;
+        }
+        let type_name = raw.type_info().name().to_string();
+        let value = match type_name.as_str() {
+            "BOOL" => row.try_get::<bool, _>(i).map(DbValue::Bool),
+            "INT2" => row.try_get::<i16, _>(i).map(|v| DbValue::Int(v.into())),
+            "INT4" => row.try_get::<i32, _>(i).map(|v| DbValue::Int(v.into())),
+            "INT8" => row.try_get::<i64, _>(i).map(DbValue::Int),
+            "FLOAT4" => row.try_get::<f32, _>(i).map(|v| DbValue::Float(v.into())),
+            "FLOAT8" => row.try_get::<f64, _>(i).map(DbValue::Float),
+            "TEXT" | "VARCHAR" | "BPCHAR" | "CHAR" | "NAME" => row.try_get::<String, _>(i).map(DbValue::Text),
+            "BYTEA" => row.try_get::<Vec<u8>, _>(i).map(DbValue::Bytes),
+            "UUID" => row.try_get::<Uuid, _>(i).map(DbValue::Uuid),
+            "TIMESTAMPTZ" => row.try_get::<DateTime<Utc>, _>(i).map(DbValue::Timestamp),
+            "TIMESTAMP" => row.try_get::<NaiveDateTime, _>(i).map(|v| DbValue::Timestamp(v.and_utc())),
+            other => return Err(decode_error(name, format!("unsupported type {}", other))),
+        };
+        values.push(value.map_err(|e| decode_error(name, e))?);
+    }
+    Ok(DbRow::new(columns.clone(), values))
+}
+
+/// Decodes a SQLite row by the storage class of each value.
+pub fn decode_sqlite_row(row: &SqliteRow, columns: &Arc<[String]>) -> Result<DbRow, String> {
+    let mut values = Vec::with_capacity(row.len());
+    for (i, name) in columns.iter().enumerate() {
+        let raw = row.try_get_raw(i).map_err(|e| decode_error(name, e))?;
+        if raw.is_null() {
+            values.push(DbValue::Null);
+            continue;
+        }
+        let type_name = raw.type_info().name().to_string();
+        let value = match type_name.as_str() {
+            "INTEGER" | "BOOLEAN" => row.try_get::<i64, _>(i).map(DbValue::Int),
+            "REAL" => row.try_get::<f64, _>(i).map(DbValue::Float),
+            "TEXT" => row.try_get::<String, _>(i).map(DbValue::Text),
+            "BLOB" => row.try_get::<Vec<u8>, _>(i).map(DbValue::Bytes),
+            other => return Err(decode_error(name, format!("unsupported type {}", other))),
+        };
+        values.push(value.map_err(|e| decode_error(name, e))?);
+    }
+    Ok(DbRow::new(columns.clone(), values))
+}
+
+/// Decodes every row, stopping at the first column that can not be read.
+pub fn decode_rows<R: SqlxRow>(
+    rows: &[R],
+    decode: fn(&R, &Arc<[String]>) -> Result<DbRow, String>,
+) -> Result<Vec<DbRow>, String> {
+    let Some(first) = rows.first() else {
+        return Ok(Vec::new());
+    };
+    let columns = column_names(first);
+    rows.iter().map(|row| decode(row, &columns)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(columns: &[&str], values: Vec<DbValue>) -> DbRow {
+        DbRow::new(columns.iter().map(|c| c.to_string()).collect(), values)
+    }
+
+    #[test]
+    fn lookups_ignore_case() {
+        let row = row(&["id", "passwordHash"], vec![DbValue::Int(3), DbValue::Text("h".into())]);
+        assert_eq!(row.len(), 2);
+        assert!(!row.is_empty());
+        assert_eq!(row.get("passwordhash"), Some(&DbValue::Text("h".into())));
+        assert_eq!(row.get("ID"), Some(&DbValue::Int(3)));
+        assert_eq!(row.get("banned"), None);
+    }
+
+    #[test]
+    fn try_get_names_the_column_on_failure() {
+        let row = row(&["id", "name"], vec![DbValue::Int(-1), DbValue::Null]);
+        assert!(row.try_get::<u32>("id").unwrap_err().contains("\"id\""));
+        assert!(row.try_get::<String>("missing").unwrap_err().contains("missing column"));
+        assert_eq!(row.try_get::<Option<String>>("name").unwrap(), None);
+        assert_eq!(row.try_get::<i64>("id").unwrap(), -1);
+    }
+
+    #[test]
+    fn sqlite_integers_read_as_bools_and_times() {
+        let row = row(&["banned", "lockedUntil"], vec![DbValue::Int(1), DbValue::Int(60)]);
+        assert!(row.try_get::<bool>("banned").unwrap());
+        assert_eq!(row.try_get::<SystemTime>("lockedUntil").unwrap(), UNIX_EPOCH + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn decodes_user_and_lockout_rows() {
+        let user = row(
+            &["id", "passwordHash", "banned"],
+            vec![DbValue::Int(7), DbValue::Text("hash".into()), DbValue::Bool(true)],
+        );
+        let user = UserRow::from_row(&user).unwrap();
+        assert_eq!((user.id, user.password_hash.as_str(), user.banned), (7, "hash", true));
+
+        let lockout = row(&["lockKey", "lockedUntil"], vec![DbValue::Text("account:bob".into()), DbValue::Int(10)]);
+        let lockout = Lockout::from_row(&lockout).unwrap();
+        assert_eq!(lockout.key, ThrottleKey::Account("bob".into()));
+
+        let bad = row(&["lockKey", "lockedUntil"], vec![DbValue::Text("bob".into()), DbValue::Int(10)]);
+        assert!(Lockout::from_row(&bad).is_err());
+    }
+}
@@ -0,0 +1,567 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use serde::{Deserialize, Serialize};
+
+/// Where a connection is in the login pipeline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SessionState {
+    /// Accepted, waiting for the client to send Auth.
+    Connected,
+    /// GetUser queued on the DbWorker.
+    AwaitingDb,
+    /// Password queued on the Argon2Worker.
+    AwaitingHash,
+    /// Creating a new account, hashing the password and then inserting the row.
+    Registering,
+    /// Logged in, not in the world yet.
+    Authed,
+    /// Logged in and playing.
+    InGame,
+}
+
+impl SessionState {
+    pub fn can_transition_to(&self, to: SessionState) -> bool {
+        use SessionState::*;
+        matches!(
+            (self, to),
+            (Connected, AwaitingDb)
+                | (AwaitingDb, AwaitingHash)
+                | (AwaitingDb, Connected)
+                | (AwaitingHash, Authed)
+                | (AwaitingHash, Connected)
+                | (Connected, Registering)
+                | (Registering, Connected)
+                | (Authed, InGame)
+                | (InGame, Authed)
+        )
+    }
+}
+
+/// How long a connection may sit in each pre-auth stage before it gets dropped.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthTimeouts {
+    /// Waiting for the client to send Auth.
+    pub connected: Duration,
+    /// Waiting for the GetUser result.
+    pub awaiting_db: Duration,
+    /// Waiting for the Argon2 verification.
+    pub awaiting_hash: Duration,
+    /// Waiting for a new account to be hashed and stored.
+    pub registering: Duration,
+}
+
+impl Default for AuthTimeouts {
+    fn default() -> Self {
+        Self {
+            connected: Duration::from_secs(30),
+            awaiting_db: Duration::from_secs(10),
+            awaiting_hash: Duration::from_secs(10),
+            registering: Duration::from_secs(20),
+        }
+    }
+}
+
+impl AuthTimeouts {
+    /// None for stages that never expire.
+    pub fn for_state(&self, state: SessionState) -> Option<Duration> {
+        match state {
+            SessionState::Connected => Some(self.connected),
+            SessionState::AwaitingDb => Some(self.awaiting_db),
+            SessionState::AwaitingHash => Some(self.awaiting_hash),
+            SessionState::Registering => Some(self.registering),
+            SessionState::Authed | SessionState::InGame => None,
+        }
+    }
+}
+
+/// What to do when an account logs in while it already has a live session.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DuplicateLoginPolicy {
+    /// Refuse the new login, the player already in keeps playing.
+    RejectNew,
+    /// Close the existing session, the new login takes over.
+    #[default]
+    KickExisting,
+}
+
+/// What DuplicateLoginPolicy says about a login whose account is already in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DuplicateLogin<K> {
+    /// Refuse the new login with AlreadyLoggedIn.
+    Reject,
+    /// Close this session, the new login takes its place.
+    Kick(K),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionError {
+    UnknownSession,
+    InvalidTransition { from: SessionState, to: SessionState },
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SessionError::UnknownSession => write!(f, "unknown session"),
+            SessionError::InvalidTransition { from, to } => {
+                write!(f, "invalid session transition {:?} -> {:?}", from, to)
+            }
+        }
+    }
+}
+
+impl std::error::Error for SessionError {}
+
+#[derive(Debug, Clone)]
+pub struct ConnectedClient {
+    pub is_authed: bool,
+    pub db_id: u32,
+    pub last_known_world_tick: u64,
+}
+
+impl ConnectedClient {
+    pub fn new() -> Self {
+        Self {
+            is_authed: false,
+            db_id: 0,
+            last_known_world_tick: 0,
+        }
+    }
+}
+
+impl Default for ConnectedClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AuthRequest {
+    pub tmp_id: u64,
+    pub username: String,
+    pub provided_password: String,
+    pub db_id: u32,
+    pub db_hash: String,
+    pub banned: bool,
+    pub attempts: u32,
+}
+
+impl AuthRequest {
+    pub fn new(
+        tmp_id: u64,
+        username: String,
+        provided_password: String,
+        db_id: u32,
+        db_hash: String,
+    ) -> Self {
+        Self {
+            tmp_id,
+            username,
+            provided_password,
+            db_id,
+            db_hash,
+            banned: false,
+            attempts: 0,
+        }
+    }
+
+    /// Drops the secrets once they are no longer needed.
+    pub fn clear_credentials(&mut self) {
+        self.provided_password.clear();
+        self.db_hash.clear();
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Session {
+    pub state: SessionState,
+    /// When the session entered its current state.
+    pub state_since: Instant,
+    pub remote_addr: Option<IpAddr>,
+    /// Session token issued on login, see SessionTokens.
+    pub token: Option<String>,
+    pub auth: AuthRequest,
+    pub client: ConnectedClient,
+}
+
+/// Every connection the server knows about, keyed by connection handle.
+/// All state changes go through `transition` so a client can only ever be in one stage.
+pub struct SessionRegistry<K> {
+    sessions: HashMap<K, Session>,
+}
+
+impl<K: Copy + Eq + Hash> SessionRegistry<K> {
+    pub fn new() -> Self {
+        Self {
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Registers a freshly accepted connection in the Connected state.
+    /// An existing session for the same key is replaced.
+    pub fn insert(&mut self, key: K, tmp_id: u64, remote_addr: Option<IpAddr>) {
+        let auth = AuthRequest::new(tmp_id, String::new(), String::new(), 0, String::new());
+        self.sessions.insert(
+            key,
+            Session {
+                state: SessionState::Connected,
+                state_since: Instant::now(),
+                remote_addr,
+                token: None,
+                auth,
+                client: ConnectedClient::new(),
+            },
+        );
+    }
+
+    pub fn get(&self, key: &K) -> Option<&Session> {
+        self.sessions.get(key)
+    }
+
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut Session> {
+        self.sessions.get_mut(key)
+    }
+
+    pub fn state(&self, key: &K) -> Option<SessionState> {
+        self.sessions.get(key).map(|s| s.state)
+    }
+
+    /// Returns the session only if it is currently in `state`.
+    pub fn get_in(&mut self, key: &K, state: SessionState) -> Option<&mut Session> {
+        self.sessions.get_mut(key).filter(|s| s.state == state)
+    }
+
+    pub fn transition(&mut self, key: &K, to: SessionState) -> Result<&mut Session, SessionError> {
+        let session = self.sessions.get_mut(key).ok_or(SessionError::UnknownSession)?;
+        if !session.state.can_transition_to(to) {
+            return Err(SessionError::InvalidTransition { from: session.state, to });
+        }
+        session.state = to;
+        session.state_since = Instant::now();
+        Ok(session)
+    }
+
+    /// Puts a resumed player back on a new connection, skipping the login stages.
+    /// Only a Connected session can be resumed, and only into Authed or InGame.
+    pub fn resume(
+        &mut self,
+        key: &K,
+        to: SessionState,
+        client: ConnectedClient,
+    ) -> Result<&mut Session, SessionError> {
+        let session = self.sessions.get_mut(key).ok_or(SessionError::UnknownSession)?;
+        let allowed = session.state == SessionState::Connected
+            && matches!(to, SessionState::Authed | SessionState::InGame);
+        if !allowed {
+            return Err(SessionError::InvalidTransition { from: session.state, to });
+        }
+        session.state = to;
+        session.state_since = Instant::now();
+        session.client = client;
+        Ok(session)
+    }
+
+    /// Removes and returns every session that overstayed its stage deadline.
+    pub fn drain_expired(&mut self, now: Instant, timeouts: &AuthTimeouts) -> Vec<(K, Session)> {
+        let expired: Vec<K> = self
+            .sessions
+            .iter()
+            .filter(|(_, s)| {
+                timeouts
+                    .for_state(s.state)
+                    .is_some_and(|limit| now.saturating_duration_since(s.state_since) >= limit)
+            })
+            .map(|(k, _)| *k)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|k| self.sessions.remove(&k).map(|s| (k, s)))
+            .collect()
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<Session> {
+        self.sessions.remove(key)
+    }
+
+    /// Finds another logged in session (Authed or InGame) of the same account.
+    pub fn find_logged_in(&self, db_id: u32, except: &K) -> Option<K> {
+        self.sessions
+            .iter()
+            .find(|(k, s)| {
+                *k != except
+                    && matches!(s.state, SessionState::Authed | SessionState::InGame)
+                    && s.client.db_id == db_id
+            })
+            .map(|(k, _)| *k)
+    }
+
+    /// Decides a login of `db_id` on `key` that just verified. None when the account has
+    /// no other logged in session. Two connections may verify the same account at once,
+    /// whichever finishes second is the one that gets here with Some.
+    pub fn duplicate_login(&self, db_id: u32, key: &K, policy: DuplicateLoginPolicy) -> Option<DuplicateLogin<K>> {
+        let existing = self.find_logged_in(db_id, key)?;
+        Some(match policy {
+            DuplicateLoginPolicy::RejectNew => DuplicateLogin::Reject,
+            DuplicateLoginPolicy::KickExisting => DuplicateLogin::Kick(existing),
+        })
+    }
+
+    /// Removes every session, for shutdown.
+    pub fn drain(&mut self) -> Vec<(K, Session)> {
+        self.sessions.drain().collect()
+    }
+
+    pub fn count_in(&self, state: SessionState) -> usize {
+        self.sessions.values().filter(|s| s.state == state).count()
+    }
+
+    /// Players taking up a slot against max_clients, Authed or InGame.
+    pub fn logged_in(&self) -> usize {
+        self.count_in(SessionState::Authed) + self.count_in(SessionState::InGame)
+    }
+
+    pub fn len(&self) -> usize {
+        self.sessions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sessions.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Session)> {
+        self.sessions.iter()
+    }
+}
+
+impl<K: Copy + Eq + Hash> Default for SessionRegistry<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use SessionState::*;
+
+    const ALL: [SessionState; 6] = [Connected, AwaitingDb, AwaitingHash, Registering, Authed, InGame];
+
+    const ALLOWED: [(SessionState, SessionState); 9] = [
+        (Connected, AwaitingDb),
+        (AwaitingDb, AwaitingHash),
+        (AwaitingDb, Connected),
+        (AwaitingHash, Authed),
+        (AwaitingHash, Connected),
+        (Connected, Registering),
+        (Registering, Connected),
+        (Authed, InGame),
+        (InGame, Authed),
+    ];
+
+    fn timeouts() -> AuthTimeouts {
+        AuthTimeouts {
+            connected: Duration::from_secs(1),
+            awaiting_db: Duration::from_secs(2),
+            awaiting_hash: Duration::from_secs(3),
+            registering: Duration::from_secs(4),
+        }
+    }
+
+    /// A registry holding session 1, walked into `state` through allowed transitions.
+    fn registry_in(state: SessionState) -> SessionRegistry<u32> {
+        let path: &[SessionState] = match state {
+            Connected => &[],
+            AwaitingDb => &[AwaitingDb],
+            AwaitingHash => &[AwaitingDb, AwaitingHash],
+            Registering => &[Registering],
+            Authed => &[AwaitingDb, AwaitingHash, Authed],
+            InGame => &[AwaitingDb, AwaitingHash, Authed, InGame],
+        };
+        let mut sessions = SessionRegistry::new();
+        sessions.insert(1, 100, None);
+        for step in path {
+            sessions.transition(&1, *step).unwrap();
+        }
+        assert_eq!(sessions.state(&1), Some(state));
+        sessions
+    }
+
+    #[test]
+    fn can_transition_to_allows_only_the_pipeline_edges() {
+        for from in ALL {
+            for to in ALL {
+                let expected = ALLOWED.contains(&(from, to));
+                assert_eq!(from.can_transition_to(to), expected, "{:?} -> {:?}", from, to);
+            }
+        }
+    }
+
+    #[test]
+    fn transition_applies_allowed_pairs_and_refuses_the_rest() {
+        for from in ALL {
+            for to in ALL {
+                let mut sessions = registry_in(from);
+                let result = sessions.transition(&1, to).map(|s| s.state);
+                if ALLOWED.contains(&(from, to)) {
+                    assert_eq!(result, Ok(to), "{:?} -> {:?}", from, to);
+                } else {
+                    assert_eq!(result, Err(SessionError::InvalidTransition { from, to }));
+                    assert_eq!(sessions.state(&1), Some(from));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn transition_of_unknown_session_fails() {
+        let mut sessions: SessionRegistry<u32> = SessionRegistry::new();
+        assert_eq!(sessions.transition(&1, AwaitingDb).map(|s| s.state), Err(SessionError::UnknownSession));
+    }
+
+    #[test]
+    fn get_in_only_returns_sessions_in_that_state() {
+        for actual in ALL {
+            let mut sessions = registry_in(actual);
+            for asked in ALL {
+                assert_eq!(sessions.get_in(&1, asked).is_some(), asked == actual, "{:?} asked as {:?}", actual, asked);
+            }
+            assert!(sessions.get_in(&2, actual).is_none());
+        }
+    }
+
+    #[test]
+    fn resume_only_from_connected_into_logged_in_states() {
+        for from in ALL {
+            for to in ALL {
+                let mut sessions = registry_in(from);
+                let client = ConnectedClient { is_authed: true, db_id: 7, last_known_world_tick: 42 };
+                let result = sessions.resume(&1, to, client).map(|s| s.state);
+                if from == Connected && matches!(to, Authed | InGame) {
+                    assert_eq!(result, Ok(to));
+                    assert_eq!(sessions.get(&1).unwrap().client.db_id, 7);
+                } else {
+                    assert_eq!(result, Err(SessionError::InvalidTransition { from, to }), "{:?} -> {:?}", from, to);
+                    assert_eq!(sessions.state(&1), Some(from));
+                    assert_eq!(sessions.get(&1).unwrap().client.db_id, 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn drain_expired_uses_the_deadline_of_each_stage() {
+        let timeouts = timeouts();
+        for state in ALL {
+            let mut sessions = registry_in(state);
+            let since = sessions.get(&1).unwrap().state_since;
+            match timeouts.for_state(state) {
+                Some(limit) => {
+                    let just_before = since + limit - Duration::from_millis(1);
+                    assert!(sessions.drain_expired(just_before, &timeouts).is_empty(), "{:?}", state);
+                    let expired = sessions.drain_expired(since + limit, &timeouts);
+                    assert_eq!(expired.len(), 1, "{:?}", state);
+                    assert_eq!(expired[0].0, 1);
+                    assert!(sessions.is_empty());
+                }
+                None => {
+                    let much_later = since + Duration::from_secs(3600);
+                    assert!(sessions.drain_expired(much_later, &timeouts).is_empty(), "{:?}", state);
+                    assert_eq!(sessions.len(), 1);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn drain_expired_keeps_sessions_within_their_deadline() {
+        let timeouts = timeouts();
+        let mut sessions = registry_in(Connected);
+        sessions.insert(2, 101, None);
+        sessions.transition(&2, Registering).unwrap();
+        let now = sessions.get(&2).unwrap().state_since + Duration::from_secs(2);
+
+        let expired: Vec<u32> = sessions.drain_expired(now, &timeouts).into_iter().map(|(k, _)| k).collect();
+        assert_eq!(expired, vec![1]);
+        assert_eq!(sessions.state(&2), Some(Registering));
+    }
+
+    /// Session `key` with its password for account `db_id` being verified.
+    fn verifying(sessions: &mut SessionRegistry<u32>, key: u32, db_id: u32) {
+        sessions.insert(key, key as u64, None);
+        sessions.transition(&key, AwaitingDb).unwrap();
+        sessions.transition(&key, AwaitingHash).unwrap().auth.db_id = db_id;
+    }
+
+    /// What the net loop does once a password checked out.
+    fn finish_login(sessions: &mut SessionRegistry<u32>, key: u32, policy: DuplicateLoginPolicy) -> Option<DuplicateLogin<u32>> {
+        let db_id = sessions.get(&key).unwrap().auth.db_id;
+        let decision = sessions.duplicate_login(db_id, &key, policy);
+        match decision {
+            Some(DuplicateLogin::Reject) => {
+                sessions.transition(&key, Connected).unwrap();
+                return decision;
+            }
+            Some(DuplicateLogin::Kick(existing)) => {
+                sessions.remove(&existing);
+            }
+            None => {}
+        }
+        let session = sessions.transition(&key, Authed).unwrap();
+        session.client.is_authed = true;
+        session.client.db_id = db_id;
+        decision
+    }
+
+    #[test]
+    fn concurrent_logins_follow_the_duplicate_login_policy_in_either_order() {
+        for policy in [DuplicateLoginPolicy::RejectNew, DuplicateLoginPolicy::KickExisting] {
+            for (first, second) in [(1, 2), (2, 1)] {
+                let mut sessions = SessionRegistry::new();
+                verifying(&mut sessions, 1, 7);
+                verifying(&mut sessions, 2, 7);
+                // Another account being in must not matter
+                verifying(&mut sessions, 3, 8);
+                assert_eq!(finish_login(&mut sessions, 3, policy), None);
+
+                // The other connection is still verifying, it does not count yet
+                assert_eq!(finish_login(&mut sessions, first, policy), None);
+                assert_eq!(sessions.state(&first), Some(Authed));
+
+                let decision = finish_login(&mut sessions, second, policy);
+                match policy {
+                    DuplicateLoginPolicy::RejectNew => {
+                        assert_eq!(decision, Some(DuplicateLogin::Reject));
+                        assert_eq!(sessions.state(&first), Some(Authed));
+                        assert_eq!(sessions.state(&second), Some(Connected));
+                    }
+                    DuplicateLoginPolicy::KickExisting => {
+                        assert_eq!(decision, Some(DuplicateLogin::Kick(first)));
+                        assert_eq!(sessions.state(&first), None);
+                        assert_eq!(sessions.state(&second), Some(Authed));
+                    }
+                }
+                let in_as_7 = sessions.iter().filter(|(_, s)| s.state == Authed && s.client.db_id == 7).count();
+                assert_eq!(in_as_7, 1);
+                assert_eq!(sessions.state(&3), Some(Authed));
+            }
+        }
+    }
+
+    #[test]
+    fn duplicate_login_counts_sessions_in_game() {
+        let mut sessions = SessionRegistry::new();
+        verifying(&mut sessions, 1, 7);
+        finish_login(&mut sessions, 1, DuplicateLoginPolicy::KickExisting);
+        sessions.transition(&1, InGame).unwrap();
+        verifying(&mut sessions, 2, 7);
+
+        assert_eq!(sessions.duplicate_login(7, &2, DuplicateLoginPolicy::KickExisting), Some(DuplicateLogin::Kick(1)));
+        assert_eq!(sessions.duplicate_login(7, &2, DuplicateLoginPolicy::RejectNew), Some(DuplicateLogin::Reject));
+        // A session never runs into itself
+        assert_eq!(sessions.duplicate_login(7, &1, DuplicateLoginPolicy::RejectNew), None);
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::HashMap;
+use sqlx::{Either, Executor, Statement, TypeInfo};
+
+use crate::db::{DbBackend, DbPool, DbStmt};
+
+/// What a parameter has to look like. Every parameter is bound as text, the SQL casts
+/// where the column wants something else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    Text,
+    /// A decimal i64, ids and unix seconds.
+    Int,
+}
+
+impl ParamType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ParamType::Text => "text",
+            ParamType::Int => "int",
+        }
+    }
+
+    fn accepts(&self, value: &str) -> bool {
+        match self {
+            ParamType::Text => true,
+            ParamType::Int => value.parse::<i64>().is_ok(),
+        }
+    }
+
+    /// Whether the type Postgres inferred for the placeholder agrees with this one.
+    /// SQLite infers nothing, there only the count is checked.
+    fn matches_pg(&self, type_name: &str) -> bool {
+        match self {
+            ParamType::Text => matches!(type_name, "TEXT" | "VARCHAR" | "BPCHAR" | "CHAR" | "NAME"),
+            ParamType::Int => matches!(type_name, "INT2" | "INT4" | "INT8"),
+        }
+    }
+}
+
+/// A statement shipped inside the binary, written once per dialect under
+/// statements/<dialect>/<name>.sql. Both dialects must return the same column names,
+/// see the row types in rows.rs.
+pub struct StatementDef {
+    /// DbStmt::as_str of the statement.
+    pub name: &'static str,
+    pub params: &'static [ParamType],
+    pub postgres: &'static str,
+    pub sqlite: &'static str,
+}
+
+impl StatementDef {
+    pub fn sql(&self, backend: DbBackend) -> &'static str {
+        match backend {
+            DbBackend::Postgres => self.postgres,
+            DbBackend::Sqlite => self.sqlite,
+        }
+    }
+}
+
+macro_rules! statement {
+    ($name:literal, [$($param:ident),*]) => {
+        StatementDef {
+            name: $name,
+            params: &[$(ParamType::$param),*],
+            postgres: include_str!(concat!("../statements/postgres/", $name, ".sql")),
+            sqlite: include_str!(concat!("../statements/sqlite/", $name, ".sql")),
+        }
+    };
+}
+
+/// Every statement the server runs.
+pub const STATEMENTS: &[StatementDef] = &[
+    statement!("get_user", [Text]),
+    statement!("get_lockouts", []),
+    statement!("save_lockout", [Text, Int]),
+    statement!("create_user", [Text, Text]),
+    statement!("update_password_hash", [Int, Text]),
+];
+
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+    sql: &'static str,
+    params: &'static [ParamType],
+}
+
+/// The statements of one backend, keyed by DbStmt.
+#[derive(Debug, Clone)]
+pub struct StatementCatalog {
+    backend: DbBackend,
+    entries: HashMap<DbStmt, Entry>,
+}
+
+impl StatementCatalog {
+    pub fn load(backend: DbBackend) -> Self {
+        let entries = STATEMENTS
+            .iter()
+            .map(|def| (DbStmt::from_name(def.name), Entry { sql: def.sql(backend), params: def.params }))
+            .collect();
+        Self { backend, entries }
+    }
+
+    pub fn backend(&self) -> DbBackend {
+        self.backend
+    }
+
+    pub fn sql(&self, stmt: &DbStmt) -> Option<&'static str> {
+        self.entries.get(stmt).map(|entry| entry.sql)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Checks `params` against what `stmt` declares, so a bad job is refused when it is
+    /// queued instead of failing later on a worker.
+    pub fn check_params(&self, stmt: &DbStmt, params: &[String]) -> Result<(), String> {
+        let entry = self.entries.get(stmt).ok_or_else(|| format!("unknown statement {}", stmt.as_str()))?;
+        if params.len() != entry.params.len() {
+            return Err(format!(
+                "{} takes {} parameters, got {}",
+                stmt.as_str(),
+                entry.params.len(),
+                params.len()
+            ));
+        }
+        for (i, (ty, value)) in entry.params.iter().zip(params).enumerate() {
+            if !ty.accepts(value) {
+                return Err(format!("{} parameter {} must be {}, got {:?}", stmt.as_str(), i + 1, ty.as_str(), value));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Placeholder count and, where the backend infers them, placeholder type names.
+fn placeholders<T: TypeInfo>(found: Option<Either<&[T], usize>>) -> Option<(usize, Vec<String>)> {
+    match found? {
+        Either::Left(types) => Some((types.len(), types.iter().map(|t| t.name().to_string()).collect())),
+        Either::Right(count) => Some((count, Vec::new())),
+    }
+}
+
+/// Prepares every statement on the live database, which fails for missing tables and
+/// columns, and compares the placeholders the database found with the declared
+/// parameters. Run after the migrations, a mismatch means the build and schema disagree.
+pub async fn validate(pool: &DbPool, catalog: &StatementCatalog) -> Result<(), String> {
+    let mut entries: Vec<_> = catalog.entries.iter().collect();
+    entries.sort_by_key(|(stmt, _)| stmt.as_str().to_string());
+
+    for (stmt, entry) in entries {
+        let fail = |e: sqlx::Error| format!("statement {} does not fit the schema: {}", stmt.as_str(), e);
+        let found = match pool {
+            DbPool::Postgres(pool) => placeholders(pool.prepare(entry.sql).await.map_err(fail)?.parameters()),
+            DbPool::Sqlite(pool) => placeholders(pool.prepare(entry.sql).await.map_err(fail)?.parameters()),
+        };
+        let Some((count, types)) = found else {
+            continue;
+        };
+        if count != entry.params.len() {
+            return Err(format!(
+                "statement {} declares {} parameters but its SQL has {}",
+                stmt.as_str(),
+                entry.params.len(),
+                count
+            ));
+        }
+        for (i, (ty, name)) in entry.params.iter().zip(&types).enumerate() {
+            if !ty.matches_pg(name) {
+                return Err(format!(
+                    "statement {} parameter {} is declared {} but the database expects {}",
+                    stmt.as_str(),
+                    i + 1,
+                    ty.as_str(),
+                    name
+                ));
+            }
+        }
+    }
+    log::info!("Checked {} statements against the schema", catalog.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use crate::migrations;
+
+    const BUILT_IN: [DbStmt; 5] = [
+        DbStmt::GetUser,
+        DbStmt::GetLockouts,
+        DbStmt::SaveLockout,
+        DbStmt::CreateUser,
+        DbStmt::UpdatePasswordHash,
+    ];
+
+    fn migrated_memory_pool(runtime: &tokio::runtime::Runtime) -> DbPool {
+        runtime.block_on(async {
+            let pool = DbPool::connect("sqlite::memory:", 1, Duration::from_secs(1), Duration::from_secs(1)).await.unwrap();
+            migrations::apply(&pool).await.unwrap();
+            pool
+        })
+    }
+
+    #[test]
+    fn every_statement_is_in_both_catalogs() {
+        for backend in [DbBackend::Postgres, DbBackend::Sqlite] {
+            let catalog = StatementCatalog::load(backend);
+            assert_eq!(catalog.backend(), backend);
+            assert_eq!(catalog.len(), BUILT_IN.len());
+            assert!(!catalog.is_empty());
+            for stmt in &BUILT_IN {
+                assert_eq!(DbStmt::from_name(stmt.as_str()), *stmt);
+                assert!(catalog.sql(stmt).is_some_and(|sql| !sql.trim().is_empty()), "{:?}", stmt);
+            }
+            assert_eq!(catalog.sql(&DbStmt::from_name("nope")), None);
+        }
+    }
+
+    #[test]
+    fn params_are_checked_against_the_declaration() {
+        let catalog = StatementCatalog::load(DbBackend::Sqlite);
+        catalog.check_params(&DbStmt::SaveLockout, &["account:a".into(), "12".into()]).unwrap();
+
+        let wrong_count = catalog.check_params(&DbStmt::GetUser, &[]).unwrap_err();
+        assert!(wrong_count.contains("takes 1 parameters, got 0"), "{}", wrong_count);
+        let not_int = catalog.check_params(&DbStmt::UpdatePasswordHash, &["x".into(), "h".into()]).unwrap_err();
+        assert!(not_int.contains("parameter 1 must be int"), "{}", not_int);
+        assert!(catalog.check_params(&DbStmt::Custom("nope".into()), &[]).is_err());
+    }
+
+    #[test]
+    fn statements_fit_the_migrated_schema() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let pool = migrated_memory_pool(&runtime);
+        runtime.block_on(validate(&pool, &StatementCatalog::load(DbBackend::Sqlite))).unwrap();
+    }
+
+    #[test]
+    fn mismatched_statements_are_reported() {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let pool = migrated_memory_pool(&runtime);
+        let check = |sql: &'static str, params: &'static [ParamType]| {
+            let catalog = StatementCatalog {
+                backend: DbBackend::Sqlite,
+                entries: HashMap::from([(DbStmt::GetUser, Entry { sql, params })]),
+            };
+            runtime.block_on(validate(&pool, &catalog))
+        };
+
+        let extra = check(r#"SELECT id FROM "USER" WHERE userName = ?1"#, &[ParamType::Text, ParamType::Text]).unwrap_err();
+        assert!(extra.contains("declares 2 parameters but its SQL has 1"), "{}", extra);
+        let missing = check(r#"SELECT nope FROM "USER" WHERE userName = ?1"#, &[ParamType::Text]).unwrap_err();
+        assert!(missing.contains("does not fit the schema"), "{}", missing);
+    }
+}
@@ -0,0 +1,451 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Tuning for login throttling. Failures are counted separately per account and per address,
+/// registrations per address in a budget of their own.
+#[derive(Debug, Clone, Copy)]
+pub struct ThrottlePolicy {
+    /// Failures allowed before any delay kicks in.
+    pub free_attempts: u32,
+    /// Delay after the first throttled failure, doubled on each further one.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    /// Failures that turn the backoff into a lockout.
+    pub lockout_threshold: u32,
+    pub lockout_duration: Duration,
+    /// A key with no failures for this long starts from zero again.
+    pub forget_after: Duration,
+    /// Registrations from one address before the backoff starts, and before it turns into a lockout.
+    pub registration_free_attempts: u32,
+    pub registration_lockout_threshold: u32,
+}
+
+impl Default for ThrottlePolicy {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            lockout_threshold: 10,
+            lockout_duration: Duration::from_secs(15 * 60),
+            forget_after: Duration::from_secs(60 * 60),
+            registration_free_attempts: 5,
+            registration_lockout_threshold: 20,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum ThrottleKey {
+    Account(String),
+    Address(IpAddr),
+    /// Registrations from an address, kept apart so signups never lock out logins.
+    Registration(IpAddr),
+}
+
+impl ThrottleKey {
+    /// Key as stored in the lockout table, "account:<name>", "addr:<ip>" or "register:<ip>".
+    pub fn to_db_key(&self) -> String {
+        match self {
+            ThrottleKey::Account(name) => format!("account:{}", name),
+            ThrottleKey::Address(addr) => format!("addr:{}", addr),
+            ThrottleKey::Registration(addr) => format!("register:{}", addr),
+        }
+    }
+
+    pub fn from_db_key(s: &str) -> Option<Self> {
+        if let Some(name) = s.strip_prefix("account:") {
+            Some(ThrottleKey::Account(name.to_string()))
+        } else if let Some(addr) = s.strip_prefix("addr:") {
+            addr.parse().ok().map(ThrottleKey::Address)
+        } else if let Some(addr) = s.strip_prefix("register:") {
+            addr.parse().ok().map(ThrottleKey::Registration)
+        } else {
+            None
+        }
+    }
+
+    // Usernames are case-sensitive in the USER table, so "Bob" and "bob" are two accounts here too
+    fn account(username: &str) -> Self {
+        ThrottleKey::Account(username.to_string())
+    }
+}
+
+/// Why a login attempt was refused before reaching the DB.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThrottleDecision {
+    Allow,
+    Backoff(Duration),
+    LockedOut(Duration),
+}
+
+/// A lockout that just started and has to be persisted.
+#[derive(Debug, Clone)]
+pub struct Lockout {
+    pub key: ThrottleKey,
+    pub until: SystemTime,
+}
+
+#[derive(Debug, Clone)]
+struct FailureRecord {
+    failures: u32,
+    last_failure: Instant,
+    blocked_until: Option<Instant>,
+    // Wall clock so it can be stored and survive restarts
+    locked_until: Option<SystemTime>,
+    /// Attempts still waiting for their password check.
+    in_flight: u32,
+}
+
+impl FailureRecord {
+    fn new(now: Instant) -> Self {
+        Self {
+            failures: 0,
+            last_failure: now,
+            blocked_until: None,
+            locked_until: None,
+            in_flight: 0,
+        }
+    }
+}
+
+pub struct LoginThrottle {
+    policy: ThrottlePolicy,
+    records: HashMap<ThrottleKey, FailureRecord>,
+}
+
+impl LoginThrottle {
+    pub fn new(policy: ThrottlePolicy) -> Self {
+        Self {
+            policy,
+            records: HashMap::new(),
+        }
+    }
+
+    /// Checks both the account and the address, the stricter answer wins.
+    pub fn check(&self, username: &str, addr: Option<IpAddr>) -> ThrottleDecision {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let mut decision = self.check_key(&ThrottleKey::account(username), now, wall_now);
+        if let Some(addr) = addr {
+            let by_addr = self.check_key(&ThrottleKey::Address(addr), now, wall_now);
+            decision = Self::stricter(decision, by_addr);
+        }
+        decision
+    }
+
+    /// Checks only the address, for requests that are not about an existing account.
+    pub fn check_address(&self, addr: Option<IpAddr>) -> ThrottleDecision {
+        match addr {
+            Some(addr) => self.check_key(&ThrottleKey::Address(addr), Instant::now(), SystemTime::now()),
+            None => ThrottleDecision::Allow,
+        }
+    }
+
+    /// Checks the registration budget of the address. An address locked out of
+    /// logging in does not get to burn hashes on signups either.
+    pub fn check_registration(&self, addr: Option<IpAddr>) -> ThrottleDecision {
+        let Some(addr) = addr else {
+            return ThrottleDecision::Allow;
+        };
+        let by_registration = self.check_key(&ThrottleKey::Registration(addr), Instant::now(), SystemTime::now());
+        Self::stricter(by_registration, self.check_address(Some(addr)))
+    }
+
+    fn check_key(&self, key: &ThrottleKey, now: Instant, wall_now: SystemTime) -> ThrottleDecision {
+        let Some(record) = self.records.get(key) else {
+            return ThrottleDecision::Allow;
+        };
+        if let Some(left) = record.locked_until.and_then(|until| until.duration_since(wall_now).ok()) {
+            return ThrottleDecision::LockedOut(left);
+        }
+        match record.blocked_until {
+            Some(until) if until > now => return ThrottleDecision::Backoff(until - now),
+            _ => {}
+        }
+        // Attempts in flight count as failures until their result is in, so parallel
+        // connections get no more guesses than one connection trying in a row.
+        if record.in_flight > 0 && record.failures + record.in_flight > self.policy.free_attempts {
+            return ThrottleDecision::Backoff(self.policy.base_delay);
+        }
+        ThrottleDecision::Allow
+    }
+
+    fn stricter(a: ThrottleDecision, b: ThrottleDecision) -> ThrottleDecision {
+        use ThrottleDecision::*;
+        match (a, b) {
+            (LockedOut(x), LockedOut(y)) => LockedOut(x.max(y)),
+            (LockedOut(x), _) | (_, LockedOut(x)) => LockedOut(x),
+            (Backoff(x), Backoff(y)) => Backoff(x.max(y)),
+            (Backoff(x), Allow) | (Allow, Backoff(x)) => Backoff(x),
+            (Allow, Allow) => Allow,
+        }
+    }
+
+    /// Marks a login as in flight from the moment its lookup is queued until
+    /// [`end_attempt`](Self::end_attempt), so `check` already counts it.
+    pub fn begin_attempt(&mut self, username: &str, addr: Option<IpAddr>) {
+        let now = Instant::now();
+        for key in Self::login_keys(username, addr) {
+            self.records.entry(key).or_insert_with(|| FailureRecord::new(now)).in_flight += 1;
+        }
+    }
+
+    /// Settles an attempt started with [`begin_attempt`](Self::begin_attempt). Call it once the
+    /// password check is in, before recording the outcome, or when the attempt is abandoned.
+    pub fn end_attempt(&mut self, username: &str, addr: Option<IpAddr>) {
+        for key in Self::login_keys(username, addr) {
+            if let Some(record) = self.records.get_mut(&key) {
+                record.in_flight = record.in_flight.saturating_sub(1);
+            }
+        }
+    }
+
+    fn login_keys(username: &str, addr: Option<IpAddr>) -> Vec<ThrottleKey> {
+        let mut keys = vec![ThrottleKey::account(username)];
+        if let Some(addr) = addr {
+            keys.push(ThrottleKey::Address(addr));
+        }
+        keys
+    }
+
+    /// Counts a failed login. Returns the lockouts that started because of it.
+    pub fn record_failure(&mut self, username: &str, addr: Option<IpAddr>) -> Vec<Lockout> {
+        Self::login_keys(username, addr)
+            .into_iter()
+            .filter_map(|key| self.record_key_failure(key))
+            .collect()
+    }
+
+    /// Counts a registration against the address's registration budget, each one costs
+    /// a full Argon2 hash whether or not the name turns out to be free.
+    pub fn record_registration(&mut self, addr: Option<IpAddr>) -> Option<Lockout> {
+        self.record_key_failure(ThrottleKey::Registration(addr?))
+    }
+
+    fn record_key_failure(&mut self, key: ThrottleKey) -> Option<Lockout> {
+        let now = Instant::now();
+        let policy = match key {
+            ThrottleKey::Registration(_) => ThrottlePolicy {
+                free_attempts: self.policy.registration_free_attempts,
+                lockout_threshold: self.policy.registration_lockout_threshold,
+                ..self.policy
+            },
+            _ => self.policy,
+        };
+        let record = self.records.entry(key.clone()).or_insert_with(|| FailureRecord::new(now));
+        if now.duration_since(record.last_failure) >= policy.forget_after {
+            record.failures = 0;
+        }
+        record.failures += 1;
+        record.last_failure = now;
+
+        if record.failures > policy.free_attempts {
+            let exp = (record.failures - policy.free_attempts - 1).min(16);
+            let delay = policy.base_delay.saturating_mul(1 << exp).min(policy.max_delay);
+            record.blocked_until = Some(now + delay);
+        }
+        if record.failures >= policy.lockout_threshold {
+            let until = SystemTime::now() + policy.lockout_duration;
+            record.locked_until = Some(until);
+            record.failures = 0;
+            record.blocked_until = None;
+            return Some(Lockout { key, until });
+        }
+        None
+    }
+
+    /// A good password clears the account, the address keeps its history.
+    pub fn record_success(&mut self, username: &str) {
+        let key = ThrottleKey::account(username);
+        match self.records.get_mut(&key) {
+            // Other attempts on the account are still being checked, keep counting them
+            Some(record) if record.in_flight > 0 => {
+                record.failures = 0;
+                record.blocked_until = None;
+            }
+            _ => {
+                self.records.remove(&key);
+            }
+        }
+    }
+
+    /// Restores a lockout loaded from the DB.
+    pub fn apply_lockout(&mut self, lockout: Lockout) {
+        let record = self.records.entry(lockout.key).or_insert_with(|| FailureRecord::new(Instant::now()));
+        record.locked_until = Some(lockout.until);
+    }
+
+    /// Lockouts still running, to persist them again after saving failed.
+    pub fn active_lockouts(&self) -> Vec<Lockout> {
+        let wall_now = SystemTime::now();
+        self.records
+            .iter()
+            .filter_map(|(key, r)| r.locked_until.filter(|until| *until > wall_now).map(|until| Lockout { key: key.clone(), until }))
+            .collect()
+    }
+
+    /// Forgets keys that have been quiet for a while and have no active lockout.
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        let wall_now = SystemTime::now();
+        let forget_after = self.policy.forget_after;
+        self.records.retain(|_, r| {
+            let locked = r.locked_until.is_some_and(|until| until > wall_now);
+            locked || r.in_flight > 0 || now.duration_since(r.last_failure) < forget_after
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+    const OTHER: IpAddr = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+    fn throttle() -> LoginThrottle {
+        LoginThrottle::new(ThrottlePolicy {
+            free_attempts: 2,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(4),
+            lockout_threshold: 6,
+            registration_free_attempts: 3,
+            registration_lockout_threshold: 8,
+            ..ThrottlePolicy::default()
+        })
+    }
+
+    fn backoff(decision: ThrottleDecision) -> Duration {
+        match decision {
+            ThrottleDecision::Backoff(left) => left,
+            other => panic!("expected a backoff, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn backoff_starts_after_the_free_attempts_and_doubles() {
+        let mut throttle = throttle();
+        for _ in 0..2 {
+            assert!(throttle.record_failure("bob", None).is_empty());
+            assert_eq!(throttle.check("bob", None), ThrottleDecision::Allow);
+        }
+        let mut delays = Vec::new();
+        for _ in 0..3 {
+            throttle.record_failure("bob", None);
+            delays.push(backoff(throttle.check("bob", None)));
+        }
+        assert!(delays[0] <= Duration::from_secs(1) && delays[0] > Duration::from_millis(900));
+        assert!(delays[1] > Duration::from_millis(1900));
+        // Capped at max_delay
+        assert!(delays[2] > Duration::from_millis(3900) && delays[2] <= Duration::from_secs(4));
+        assert_eq!(throttle.check("alice", None), ThrottleDecision::Allow);
+        assert_eq!(throttle.check("Bob", None), ThrottleDecision::Allow);
+    }
+
+    #[test]
+    fn threshold_locks_out_account_and_address() {
+        let mut throttle = throttle();
+        for _ in 0..5 {
+            assert!(throttle.record_failure("bob", Some(ADDR)).is_empty());
+        }
+        let lockouts = throttle.record_failure("bob", Some(ADDR));
+        let keys: Vec<_> = lockouts.iter().map(|l| l.key.clone()).collect();
+        assert_eq!(keys, [ThrottleKey::Account("bob".into()), ThrottleKey::Address(ADDR)]);
+
+        assert!(matches!(throttle.check("bob", None), ThrottleDecision::LockedOut(_)));
+        assert!(matches!(throttle.check("alice", Some(ADDR)), ThrottleDecision::LockedOut(_)));
+        assert_eq!(throttle.check("alice", Some(OTHER)), ThrottleDecision::Allow);
+        // A good password elsewhere does not lift the address lockout
+        throttle.record_success("alice");
+        throttle.prune();
+        assert!(matches!(throttle.check_address(Some(ADDR)), ThrottleDecision::LockedOut(_)));
+    }
+
+    #[test]
+    fn success_clears_only_the_account() {
+        let mut throttle = throttle();
+        for _ in 0..3 {
+            throttle.record_failure("bob", Some(ADDR));
+        }
+        throttle.record_success("bob");
+        assert_eq!(throttle.check("bob", None), ThrottleDecision::Allow);
+        backoff(throttle.check("bob", Some(ADDR)));
+    }
+
+    #[test]
+    fn attempts_in_flight_count_before_their_result() {
+        let mut throttle = throttle();
+        // As many attempts as one connection gets without a delay may be in flight at once
+        for _ in 0..3 {
+            assert_eq!(throttle.check("bob", Some(ADDR)), ThrottleDecision::Allow);
+            throttle.begin_attempt("bob", Some(ADDR));
+        }
+        backoff(throttle.check("bob", Some(ADDR)));
+        backoff(throttle.check("alice", Some(ADDR)));
+        assert_eq!(throttle.check("alice", Some(OTHER)), ThrottleDecision::Allow);
+
+        // Abandoned attempts give their slot back
+        throttle.end_attempt("bob", Some(ADDR));
+        assert_eq!(throttle.check("bob", Some(ADDR)), ThrottleDecision::Allow);
+
+        // A success does not forget the attempts still in flight
+        throttle.end_attempt("bob", Some(ADDR));
+        throttle.record_success("bob");
+        throttle.begin_attempt("bob", None);
+        throttle.begin_attempt("bob", None);
+        backoff(throttle.check("bob", None));
+        throttle.prune();
+        backoff(throttle.check("bob", None));
+    }
+
+    #[test]
+    fn registrations_have_their_own_budget() {
+        let mut throttle = throttle();
+        assert!(throttle.record_registration(None).is_none());
+        assert_eq!(throttle.check_registration(None), ThrottleDecision::Allow);
+        for _ in 0..3 {
+            assert!(throttle.record_registration(Some(ADDR)).is_none());
+        }
+        assert_eq!(throttle.check_registration(Some(ADDR)), ThrottleDecision::Allow);
+        for _ in 0..4 {
+            assert!(throttle.record_registration(Some(ADDR)).is_none());
+        }
+        backoff(throttle.check_registration(Some(ADDR)));
+        let lockout = throttle.record_registration(Some(ADDR)).unwrap();
+        assert_eq!(lockout.key, ThrottleKey::Registration(ADDR));
+        assert!(matches!(throttle.check_registration(Some(ADDR)), ThrottleDecision::LockedOut(_)));
+        assert_eq!(throttle.check_registration(Some(OTHER)), ThrottleDecision::Allow);
+        // Signups never lock the address out of logging in
+        assert_eq!(throttle.check("bob", Some(ADDR)), ThrottleDecision::Allow);
+    }
+
+    #[test]
+    fn login_lockouts_also_stop_registrations() {
+        let mut throttle = throttle();
+        for _ in 0..6 {
+            throttle.record_failure("bob", Some(ADDR));
+        }
+        assert!(matches!(throttle.check_registration(Some(ADDR)), ThrottleDecision::LockedOut(_)));
+    }
+
+    #[test]
+    fn lockouts_round_trip_through_the_db_key() {
+        for key in [ThrottleKey::account("Bob"), ThrottleKey::Address(ADDR), ThrottleKey::Registration(ADDR)] {
+            assert_eq!(ThrottleKey::from_db_key(&key.to_db_key()), Some(key));
+        }
+        assert_eq!(ThrottleKey::from_db_key("addr:not an ip"), None);
+
+        let mut throttle = throttle();
+        let until = SystemTime::now() + Duration::from_secs(60);
+        throttle.apply_lockout(Lockout { key: ThrottleKey::account("bob"), until });
+        assert!(matches!(throttle.check("bob", None), ThrottleDecision::LockedOut(_)));
+        assert_eq!(throttle.active_lockouts().len(), 1);
+        // An expired lockout from the DB is not enforced
+        throttle.apply_lockout(Lockout { key: ThrottleKey::Address(ADDR), until: SystemTime::now() - Duration::from_secs(1) });
+        assert_eq!(throttle.check_address(Some(ADDR)), ThrottleDecision::Allow);
+        assert_eq!(throttle.active_lockouts().len(), 1);
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+use rand::RngCore;
+
+use crate::session::{ConnectedClient, SessionState};
+
+#[derive(Debug, Clone, Copy)]
+pub struct TokenPolicy {
+    /// How long a token stays valid after login, connected or not.
+    pub ttl: Duration,
+    /// How long a dropped client keeps its slot waiting for a Resume.
+    pub reconnect_grace: Duration,
+}
+
+impl Default for TokenPolicy {
+    fn default() -> Self {
+        Self {
+            ttl: Duration::from_secs(12 * 60 * 60),
+            reconnect_grace: Duration::from_secs(120),
+        }
+    }
+}
+
+/// Where the logged in player behind a token currently is.
+#[derive(Debug, Clone)]
+pub enum TicketHolder<K> {
+    /// Bound to a live connection.
+    Attached(K),
+    /// Connection dropped, the player state waits here for a Resume.
+    Parked {
+        state: SessionState,
+        client: ConnectedClient,
+        since: Instant,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct SessionTicket<K> {
+    pub db_id: u32,
+    pub username: String,
+    pub expires_at: Instant,
+    pub holder: TicketHolder<K>,
+}
+
+/// What a successful Resume hands back to the caller.
+pub struct Resumed<K> {
+    pub token: String,
+    pub db_id: u32,
+    pub username: String,
+    /// Live connection that held the token and has to be closed, if any.
+    pub replaced: Option<K>,
+    /// Player state kept while parked, None when taken from a live connection.
+    pub parked: Option<(SessionState, ConnectedClient)>,
+}
+
+/// Server-side store of issued session tokens.
+pub struct SessionTokens<K> {
+    policy: TokenPolicy,
+    tickets: HashMap<String, SessionTicket<K>>,
+}
+
+impl<K: Copy + Eq + Hash> SessionTokens<K> {
+    pub fn new(policy: TokenPolicy) -> Self {
+        Self {
+            policy,
+            tickets: HashMap::new(),
+        }
+    }
+
+    fn new_token() -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        bytes.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    /// Issues a token for a freshly authenticated connection.
+    pub fn issue(&mut self, key: K, db_id: u32, username: &str) -> String {
+        let token = Self::new_token();
+        self.tickets.insert(
+            token.clone(),
+            SessionTicket {
+                db_id,
+                username: username.to_string(),
+                expires_at: Instant::now() + self.policy.ttl,
+                holder: TicketHolder::Attached(key),
+            },
+        );
+        token
+    }
+
+    /// Keeps the player state of a dropped connection around for the grace window.
+    pub fn park(&mut self, token: &str, state: SessionState, client: ConnectedClient) {
+        if let Some(ticket) = self.tickets.get_mut(token) {
+            ticket.holder = TicketHolder::Parked {
+                state,
+                client,
+                since: Instant::now(),
+            };
+        }
+    }
+
+    /// Dropped players still within their grace window. Each keeps its slot, so a
+    /// Resume never finds the server full.
+    pub fn parked(&self) -> impl Iterator<Item = &SessionTicket<K>> {
+        let now = Instant::now();
+        let grace = self.policy.reconnect_grace;
+        self.tickets.values().filter(move |t| {
+            t.expires_at > now
+                && matches!(t.holder, TicketHolder::Parked { since, .. } if now.duration_since(since) < grace)
+        })
+    }
+
+    /// Moves a token to `key`. The old token is revoked and a new one issued,
+    /// so a leaked token only works once.
+    pub fn resume(&mut self, token: &str, key: K) -> Option<Resumed<K>> {
+        let now = Instant::now();
+        let ticket = self.tickets.remove(token)?;
+        if ticket.expires_at <= now {
+            return None;
+        }
+        let (replaced, parked) = match ticket.holder {
+            TicketHolder::Attached(old) => (Some(old), None),
+            TicketHolder::Parked { state, client, since } => {
+                if now.duration_since(since) >= self.policy.reconnect_grace {
+                    return None;
+                }
+                (None, Some((state, client)))
+            }
+        };
+        let new_token = Self::new_token();
+        self.tickets.insert(
+            new_token.clone(),
+            SessionTicket {
+                db_id: ticket.db_id,
+                username: ticket.username.clone(),
+                expires_at: ticket.expires_at,
+                holder: TicketHolder::Attached(key),
+            },
+        );
+        Some(Resumed {
+            token: new_token,
+            db_id: ticket.db_id,
+            username: ticket.username,
+            replaced,
+            parked,
+        })
+    }
+
+    pub fn revoke(&mut self, token: &str) -> Option<SessionTicket<K>> {
+        self.tickets.remove(token)
+    }
+
+    /// Revokes every token of an account, returns how many there were.
+    pub fn revoke_account(&mut self, db_id: u32) -> usize {
+        let before = self.tickets.len();
+        self.tickets.retain(|_, t| t.db_id != db_id);
+        before - self.tickets.len()
+    }
+
+    /// Drops expired tokens and parked sessions past the grace window.
+    pub fn prune(&mut self) {
+        let now = Instant::now();
+        let grace = self.policy.reconnect_grace;
+        self.tickets.retain(|_, t| {
+            if t.expires_at <= now {
+                return false;
+            }
+            match &t.holder {
+                TicketHolder::Attached(_) => true,
+                TicketHolder::Parked { since, .. } => now.duration_since(*since) < grace,
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(ttl: Duration, reconnect_grace: Duration) -> SessionTokens<u32> {
+        SessionTokens::new(TokenPolicy { ttl, reconnect_grace })
+    }
+
+    #[test]
+    fn resume_swaps_the_token_and_the_connection() {
+        let mut tokens = tokens(Duration::from_secs(60), Duration::from_secs(60));
+        let token = tokens.issue(1, 7, "bob");
+        assert_eq!(token.len(), 64);
+        assert_eq!(tokens.parked().count(), 0);
+
+        let resumed = tokens.resume(&token, 2).unwrap();
+        assert_ne!(resumed.token, token);
+        assert_eq!((resumed.db_id, resumed.username.as_str()), (7, "bob"));
+        assert_eq!(resumed.replaced, Some(1));
+        assert!(resumed.parked.is_none());
+        // The old token only worked once
+        assert!(tokens.resume(&token, 3).is_none());
+        assert!(tokens.revoke(&resumed.token).is_some());
+    }
+
+    #[test]
+    fn parked_sessions_resume_within_the_grace_window() {
+        let mut tokens = tokens(Duration::from_secs(60), Duration::from_secs(60));
+        let token = tokens.issue(1, 7, "bob");
+        tokens.park(&token, SessionState::InGame, ConnectedClient::default());
+        assert_eq!(tokens.parked().count(), 1);
+
+        let resumed = tokens.resume(&token, 2).unwrap();
+        assert!(resumed.replaced.is_none());
+        assert!(matches!(resumed.parked, Some((SessionState::InGame, _))));
+        assert_eq!(tokens.parked().count(), 0);
+    }
+
+    #[test]
+    fn expired_tokens_and_stale_parks_are_refused_and_pruned() {
+        let mut tokens = tokens(Duration::from_secs(60), Duration::ZERO);
+        let parked = tokens.issue(1, 7, "bob");
+        tokens.park(&parked, SessionState::Authed, ConnectedClient::default());
+        // Past its grace window before the next prune, the slot is free again
+        assert_eq!(tokens.parked().count(), 0);
+        assert!(tokens.resume(&parked, 2).is_none());
+
+        let mut tokens = self::tokens(Duration::ZERO, Duration::from_secs(60));
+        let expired = tokens.issue(1, 7, "bob");
+        assert!(tokens.resume(&expired, 2).is_none());
+
+        let mut tokens = self::tokens(Duration::from_secs(60), Duration::ZERO);
+        let live = tokens.issue(1, 7, "bob");
+        let stale = tokens.issue(2, 8, "carol");
+        tokens.park(&stale, SessionState::Authed, ConnectedClient::default());
+        tokens.prune();
+        assert!(tokens.revoke(&stale).is_none());
+        assert!(tokens.revoke(&live).is_some());
+    }
+
+    #[test]
+    fn revoke_account_drops_every_token_of_it() {
+        let mut tokens = tokens(Duration::from_secs(60), Duration::from_secs(60));
+        let first = tokens.issue(1, 7, "bob");
+        tokens.issue(2, 7, "bob");
+        let other = tokens.issue(3, 8, "carol");
+        assert_eq!(tokens.revoke_account(7), 2);
+        assert!(tokens.resume(&first, 4).is_none());
+        assert!(tokens.resume(&other, 4).is_some());
+    }
+}
@@ -0,0 +1,739 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Why a job was not queued. Nothing is dropped silently, the caller always gets one of these.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// The job's lane is at its `queue_capacity`, try again later or tell the client.
+    Full,
+    ShuttingDown,
+    /// Whatever the jobs need is known to be failing, see DbWorker::health.
+    Unavailable,
+    /// The job itself was refused, e.g. parameters that do not fit its statement.
+    Invalid,
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::Full => write!(f, "queue is full"),
+            QueueError::ShuttingDown => write!(f, "worker pool is shutting down"),
+            QueueError::Unavailable => write!(f, "backend is unavailable"),
+            QueueError::Invalid => write!(f, "job is invalid"),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+/// Returned for every submitted job. Cancelling it before a worker picks the job up
+/// drops the job unrun, once it started it runs to the end regardless.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// One priority class of a WorkerPool. Non-empty lanes are served in proportion to
+/// their weights, so a low weight lane is slowed down but never starved.
+#[derive(Debug, Clone)]
+pub struct LaneConfig {
+    pub name: &'static str,
+    pub weight: u32,
+    /// Jobs that may wait in this lane before submit reports Full.
+    pub queue_capacity: usize,
+}
+
+/// Thread sizing and lanes for a WorkerPool.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    /// Used in log lines, "DB" or "Argon2".
+    pub name: &'static str,
+    /// Threads kept alive even when idle, started with the pool.
+    pub min_threads: usize,
+    pub max_threads: usize,
+    /// Queued jobs per thread before another thread is spawned.
+    pub max_pending_per_thread: usize,
+    /// How long a thread above `min_threads` waits for work before it exits.
+    pub idle_timeout: Duration,
+    /// At least one, submit takes an index into this.
+    pub lanes: Vec<LaneConfig>,
+}
+
+/// What one thread is doing, keyed by its id in PoolStats.
+#[derive(Debug, Clone)]
+pub struct WorkerStats {
+    pub id: usize,
+    pub busy: bool,
+    pub jobs_done: u64,
+    pub started: Instant,
+    pub last_activity: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct LaneStats {
+    pub name: &'static str,
+    pub queued: usize,
+    /// Jobs handed to a worker from this lane.
+    pub dispatched: u64,
+    /// Jobs refused because the lane was full.
+    pub rejected: u64,
+}
+
+/// A snapshot of the pool, see WorkerPool::stats.
+#[derive(Debug, Clone)]
+pub struct PoolStats {
+    pub queued: usize,
+    pub jobs_done: u64,
+    /// Jobs dropped unrun because they were cancelled while queued.
+    pub cancelled: u64,
+    /// In PoolConfig order.
+    pub lanes: Vec<LaneStats>,
+    /// Ordered by id.
+    pub workers: Vec<WorkerStats>,
+}
+
+impl PoolStats {
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
+
+    pub fn busy(&self) -> usize {
+        self.workers.iter().filter(|w| w.busy).count()
+    }
+}
+
+struct Queued<J> {
+    job: J,
+    cancel: CancelHandle,
+}
+
+struct Lane<J> {
+    config: LaneConfig,
+    queue: VecDeque<Queued<J>>,
+    /// Smooth weighted round robin credit, see PoolState::next_job.
+    credit: i64,
+    dispatched: u64,
+    rejected: u64,
+}
+
+struct PoolState<J> {
+    lanes: Vec<Lane<J>>,
+    /// Jobs across all lanes.
+    queued: usize,
+    /// Keyed by id, a thread only ever touches its own entry so ids never shift
+    /// when another one retires.
+    workers: BTreeMap<usize, WorkerStats>,
+    /// Threads waiting on job_ready.
+    idle: usize,
+    next_worker_id: usize,
+    handles: HashMap<usize, JoinHandle<()>>,
+    jobs_done: u64,
+    cancelled: u64,
+    // set by shutdown, workers drain the queue and exit
+    shutting_down: bool,
+}
+
+pub type Handler<J> = Box<dyn FnMut(J)>;
+
+struct Shared<J> {
+    state: Mutex<PoolState<J>>,
+    /// Signalled for every queued job and on shutdown.
+    job_ready: Condvar,
+    config: PoolConfig,
+    /// Called once on every new thread, the handler it returns runs that thread's jobs.
+    /// It is built on the thread itself so it may hold thread local things like a runtime.
+    make_handler: Box<dyn Fn(usize) -> Handler<J> + Send + Sync>,
+}
+
+impl<J> PoolState<J> {
+    /// Takes the next job by smooth weighted round robin: every non-empty lane earns its
+    /// weight in credit, the richest one is served and pays the sum of the weights. Over
+    /// any stretch where lanes stay busy each gets its weight's share, interleaved rather
+    /// than in bursts.
+    fn next_job(&mut self) -> Option<Queued<J>> {
+        let total: i64 = self
+            .lanes
+            .iter()
+            .filter(|lane| !lane.queue.is_empty())
+            .map(|lane| lane.config.weight as i64)
+            .sum();
+        if total == 0 {
+            return None;
+        }
+        for lane in self.lanes.iter_mut().filter(|lane| !lane.queue.is_empty()) {
+            lane.credit += lane.config.weight as i64;
+        }
+        let lane = self
+            .lanes
+            .iter_mut()
+            .filter(|lane| !lane.queue.is_empty())
+            // max_by_key keeps the last of equals, reversed so ties go to the earlier lane
+            .rev()
+            .max_by_key(|lane| lane.credit)?;
+        lane.credit -= total;
+        lane.dispatched += 1;
+        self.queued -= 1;
+        lane.queue.pop_front()
+    }
+}
+
+impl<J> Shared<J> {
+    fn lock(&self) -> MutexGuard<'_, PoolState<J>> {
+        // Jobs run outside the lock, so a poisoned lock only means a worker died while
+        // updating its own entry. The state is still consistent enough to carry on.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// How long dropping a pool waits for its workers, see `WorkerPool::shutdown`.
+const DROP_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Takes the thread's entry out of the pool however it exits, including a panicking job.
+struct WorkerGuard<J> {
+    shared: Arc<Shared<J>>,
+    id: usize,
+}
+
+impl<J> Drop for WorkerGuard<J> {
+    fn drop(&mut self) {
+        let mut state = self.shared.lock();
+        state.workers.remove(&self.id);
+        if thread::panicking() {
+            log::error!("{} worker {} panicked", self.shared.config.name, self.id);
+        }
+    }
+}
+
+/// Elastic thread pool fed from weighted lanes. Starts `min_threads`, grows up to
+/// `max_threads` when the backlog gets long and lets surplus threads retire
+/// after `idle_timeout`.
+pub struct WorkerPool<J: Send + 'static> {
+    shared: Arc<Shared<J>>,
+}
+
+impl<J: Send + 'static> WorkerPool<J> {
+    pub fn new<F>(config: PoolConfig, make_handler: F) -> Self
+    where
+        F: Fn(usize) -> Handler<J> + Send + Sync + 'static,
+    {
+        let mut config = PoolConfig {
+            max_threads: config.max_threads.max(1),
+            min_threads: config.min_threads.min(config.max_threads.max(1)),
+            ..config
+        };
+        if config.lanes.is_empty() {
+            config.lanes.push(LaneConfig { name: "default", weight: 1, queue_capacity: usize::MAX });
+        }
+        let lanes = config
+            .lanes
+            .iter()
+            .map(|lane| Lane {
+                // A zero weight lane would never be picked while another has work
+                config: LaneConfig { weight: lane.weight.max(1), ..lane.clone() },
+                queue: VecDeque::new(),
+                credit: 0,
+                dispatched: 0,
+                rejected: 0,
+            })
+            .collect();
+        let min_threads = config.min_threads;
+        let shared = Arc::new(Shared {
+            state: Mutex::new(PoolState {
+                lanes,
+                queued: 0,
+                workers: BTreeMap::new(),
+                idle: 0,
+                next_worker_id: 0,
+                handles: HashMap::new(),
+                jobs_done: 0,
+                cancelled: 0,
+                shutting_down: false,
+            }),
+            job_ready: Condvar::new(),
+            config,
+            make_handler: Box::new(make_handler),
+        });
+
+        {
+            let mut state = shared.lock();
+            for _ in 0..min_threads {
+                Self::spawn_worker(&shared, &mut state);
+            }
+        }
+
+        Self { shared }
+    }
+
+    /// Starts one more worker. Takes the already held state guard so the worker map is
+    /// updated in the same critical section that decided to spawn.
+    fn spawn_worker(shared: &Arc<Shared<J>>, state: &mut PoolState<J>) {
+        let id = state.next_worker_id;
+        state.next_worker_id += 1;
+        let now = Instant::now();
+        state.workers.insert(id, WorkerStats { id, busy: false, jobs_done: 0, started: now, last_activity: now });
+
+        let guard = WorkerGuard { shared: shared.clone(), id };
+        let handle = thread::spawn(move || Self::run_worker(guard));
+        state.handles.retain(|_, h| !h.is_finished());
+        state.handles.insert(id, handle);
+    }
+
+    fn run_worker(guard: WorkerGuard<J>) {
+        let shared = &guard.shared;
+        let id = guard.id;
+        let config = &shared.config;
+        let mut handler = (shared.make_handler)(id);
+
+        loop {
+            let job = {
+                let mut state = shared.lock();
+                loop {
+                    if let Some(queued) = state.next_job() {
+                        if queued.cancel.is_cancelled() {
+                            state.cancelled += 1;
+                            continue;
+                        }
+                        if let Some(worker) = state.workers.get_mut(&id) {
+                            worker.busy = true;
+                            worker.last_activity = Instant::now();
+                        }
+                        break queued.job;
+                    }
+                    // Only leave once every queued job is done, pending writes must not be lost
+                    if state.shutting_down {
+                        state.workers.remove(&id);
+                        return;
+                    }
+                    state.idle += 1;
+                    let (next, wait) = shared
+                        .job_ready
+                        .wait_timeout(state, config.idle_timeout)
+                        .unwrap_or_else(|e| e.into_inner());
+                    state = next;
+                    state.idle -= 1;
+                    // Leave the map under the same lock that checked the count, so two threads
+                    // timing out together can't both retire below min_threads
+                    if wait.timed_out() && state.queued == 0 && state.workers.len() > config.min_threads {
+                        state.workers.remove(&id);
+                        log::info!("{} worker {} terminated due to inactivity", config.name, id);
+                        return;
+                    }
+                }
+            };
+
+            handler(job);
+
+            let mut state = shared.lock();
+            state.jobs_done += 1;
+            if let Some(worker) = state.workers.get_mut(&id) {
+                worker.busy = false;
+                worker.jobs_done += 1;
+                worker.last_activity = Instant::now();
+            }
+        }
+    }
+
+    /// Queues `job` in the first lane.
+    pub fn submit(&self, job: J) -> Result<CancelHandle, QueueError> {
+        self.submit_to(0, job)
+    }
+
+    /// Queues `job` in lane `lane`, an index into PoolConfig::lanes. Out of range
+    /// indexes go to the last lane.
+    pub fn submit_to(&self, lane: usize, job: J) -> Result<CancelHandle, QueueError> {
+        let config = &self.shared.config;
+        let mut guard = self.shared.lock();
+        let state = &mut *guard;
+        if state.shutting_down {
+            return Err(QueueError::ShuttingDown);
+        }
+        let index = lane.min(state.lanes.len() - 1);
+        let lane = &mut state.lanes[index];
+        if lane.queue.len() >= lane.config.queue_capacity {
+            // Cancelled jobs would only be skipped later, make room for live ones first
+            let before = lane.queue.len();
+            lane.queue.retain(|q| !q.cancel.is_cancelled());
+            let purged = before - lane.queue.len();
+            state.queued -= purged;
+            state.cancelled += purged as u64;
+            if lane.queue.len() >= lane.config.queue_capacity {
+                lane.rejected += 1;
+                return Err(QueueError::Full);
+            }
+        }
+        let cancel = CancelHandle::default();
+        lane.queue.push_back(Queued { job, cancel: cancel.clone() });
+        state.queued += 1;
+
+        // Grow when nobody is free to pick the job up and the backlog is getting long
+        let threads = state.workers.len();
+        let backlog = state.queued;
+        if state.idle == 0
+            && threads < config.max_threads
+            && (threads == 0 || backlog > threads * config.max_pending_per_thread)
+        {
+            log::info!("Spawning new {} worker thread {}", config.name, state.next_worker_id);
+            Self::spawn_worker(&self.shared, state);
+        }
+        drop(guard);
+        self.shared.job_ready.notify_one();
+        Ok(cancel)
+    }
+
+    /// Jobs waiting for a worker, across all lanes.
+    pub fn queue_len(&self) -> usize {
+        self.shared.lock().queued
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        let state = self.shared.lock();
+        PoolStats {
+            queued: state.queued,
+            jobs_done: state.jobs_done,
+            cancelled: state.cancelled,
+            lanes: state
+                .lanes
+                .iter()
+                .map(|lane| LaneStats {
+                    name: lane.config.name,
+                    queued: lane.queue.len(),
+                    dispatched: lane.dispatched,
+                    rejected: lane.rejected,
+                })
+                .collect(),
+            workers: state.workers.values().cloned().collect(),
+        }
+    }
+
+    /// Stops taking jobs, lets the workers finish what is already queued and joins them.
+    /// Returns false if they didn't all exit within `timeout`.
+    pub fn shutdown(&self, timeout: Duration) -> bool {
+        self.shared.lock().shutting_down = true;
+        self.shared.job_ready.notify_all();
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let all_done = self.shared.lock().handles.values().all(|h| h.is_finished());
+            if all_done {
+                break;
+            }
+            if Instant::now() >= deadline {
+                let state = self.shared.lock();
+                log::warn!("{} pool shutdown timed out with {} jobs left", self.shared.config.name, state.queued);
+                return false;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        let handles: Vec<_> = self.shared.lock().handles.drain().map(|(_, h)| h).collect();
+        for handle in handles {
+            let _ = handle.join();
+        }
+        true
+    }
+}
+
+/// Shuts down a pool nobody shut down explicitly, so its threads don't outlive it.
+/// Workers still busy after `DROP_SHUTDOWN_TIMEOUT` are left to finish on their own.
+impl<J: Send + 'static> Drop for WorkerPool<J> {
+    fn drop(&mut self) {
+        self.shutdown(DROP_SHUTDOWN_TIMEOUT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::mpsc::{channel, Sender};
+
+    type Job = Box<dyn FnOnce() + Send>;
+
+    fn config(min_threads: usize, max_threads: usize, idle_timeout: Duration) -> PoolConfig {
+        PoolConfig {
+            name: "test",
+            min_threads,
+            max_threads,
+            // Grow whenever nobody is idle, so every blocked job gets its own thread
+            max_pending_per_thread: 0,
+            idle_timeout,
+            lanes: Vec::new(),
+        }
+    }
+
+    fn pool(config: PoolConfig) -> WorkerPool<Job> {
+        WorkerPool::new(config, |_| Box::new(|job: Job| job()))
+    }
+
+    /// A job that holds its worker until the returned sender is used or dropped.
+    fn blocking() -> (Job, Sender<()>) {
+        let (tx, rx) = channel::<()>();
+        (
+            Box::new(move || {
+                let _ = rx.recv();
+            }),
+            tx,
+        )
+    }
+
+    fn counting(counter: &Arc<AtomicUsize>) -> Job {
+        let counter = counter.clone();
+        Box::new(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+    }
+
+    fn wait_until(what: &str, mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out waiting until {}", what);
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    fn ids(pool: &WorkerPool<Job>) -> Vec<usize> {
+        pool.stats().workers.iter().map(|w| w.id).collect()
+    }
+
+    /// Submits a blocking job and waits until a worker holds it.
+    fn occupy(pool: &WorkerPool<Job>) -> Sender<()> {
+        let busy = pool.stats().busy();
+        let (job, release) = blocking();
+        pool.submit(job).unwrap();
+        wait_until("the job runs", || pool.stats().busy() == busy + 1);
+        release
+    }
+
+    #[test]
+    fn ids_stay_stable_after_a_middle_worker_retires() {
+        let pool = pool(config(1, 3, Duration::from_millis(20)));
+        let first = occupy(&pool);
+        let middle = occupy(&pool);
+        let last = occupy(&pool);
+        assert_eq!(ids(&pool), [0, 1, 2]);
+
+        drop(middle);
+        wait_until("the middle worker retires", || pool.stats().threads() == 2);
+        assert_eq!(ids(&pool), [0, 2]);
+        assert!(pool.stats().workers.iter().all(|w| w.busy));
+
+        // A replacement gets a fresh id instead of reusing the retired one
+        let again = occupy(&pool);
+        assert_eq!(ids(&pool), [0, 2, 3]);
+        drop((first, last, again));
+        assert!(pool.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn thread_count_stays_within_bounds() {
+        // min above max is clamped to max, max to at least one
+        assert_eq!(pool(config(5, 2, Duration::from_secs(10))).stats().threads(), 2);
+        assert_eq!(pool(config(0, 0, Duration::from_secs(10))).stats().threads(), 0);
+
+        let pool = pool(config(0, 0, Duration::from_secs(10)));
+        let held = occupy(&pool);
+        let mut queued = Vec::new();
+        for _ in 0..3 {
+            let (job, release) = blocking();
+            pool.submit(job).unwrap();
+            queued.push(release);
+        }
+        assert_eq!(pool.stats().threads(), 1);
+        assert_eq!(pool.queue_len(), 3);
+        drop((held, queued));
+        assert!(pool.shutdown(Duration::from_secs(5)));
+
+        let pool = self::pool(config(1, 3, Duration::from_secs(10)));
+        let held: Vec<_> = (0..3).map(|_| occupy(&pool)).collect();
+        let (job, release) = blocking();
+        pool.submit(job).unwrap();
+        assert_eq!(pool.stats().threads(), 3);
+        assert_eq!(pool.queue_len(), 1);
+        drop((held, release));
+        assert!(pool.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn idle_retirement_keeps_min_threads() {
+        let pool = pool(config(2, 4, Duration::from_millis(10)));
+        let held: Vec<_> = (0..4).map(|_| occupy(&pool)).collect();
+        assert_eq!(pool.stats().threads(), 4);
+
+        drop(held);
+        wait_until("the surplus workers retire", || pool.stats().threads() == 2);
+        // Several more idle timeouts pass without anyone else leaving
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(pool.stats().threads(), 2);
+        assert!(pool.stats().workers.iter().all(|w| !w.busy));
+        assert!(pool.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn shutdown_runs_queued_jobs_before_joining() {
+        let pool = pool(config(1, 1, Duration::from_secs(10)));
+        let counter = Arc::new(AtomicUsize::new(0));
+        let held = occupy(&pool);
+        for _ in 0..5 {
+            pool.submit(counting(&counter)).unwrap();
+        }
+
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(held);
+        });
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        release.join().unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 5);
+        assert_eq!(pool.stats().jobs_done, 6);
+        assert_eq!(pool.stats().threads(), 0);
+        assert_eq!(pool.submit(counting(&counter)).unwrap_err(), QueueError::ShuttingDown);
+    }
+
+    #[test]
+    fn shutdown_reports_workers_that_do_not_finish_in_time() {
+        let pool = pool(config(1, 1, Duration::from_secs(10)));
+        let held = occupy(&pool);
+        assert!(!pool.shutdown(Duration::from_millis(20)));
+        drop(held);
+        assert!(pool.shutdown(Duration::from_secs(5)));
+    }
+
+    #[test]
+    fn dropping_the_pool_joins_its_workers() {
+        let pool = pool(config(2, 2, Duration::from_secs(10)));
+        let counter = Arc::new(AtomicUsize::new(0));
+        let held = occupy(&pool);
+        pool.submit(counting(&counter)).unwrap();
+        let shared = pool.shared.clone();
+
+        let release = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            drop(held);
+        });
+        drop(pool);
+        release.join().unwrap();
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+        assert!(shared.lock().shutting_down);
+        assert!(shared.lock().handles.is_empty());
+    }
+
+    #[test]
+    fn cancelled_jobs_are_skipped_and_counted() {
+        let pool = pool(config(1, 1, Duration::from_secs(10)));
+        let counter = Arc::new(AtomicUsize::new(0));
+        let held = occupy(&pool);
+        let handles: Vec<_> = (0..4).map(|_| pool.submit(counting(&counter)).unwrap()).collect();
+        handles[0].cancel();
+        handles[2].cancel();
+
+        drop(held);
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+        let stats = pool.stats();
+        assert_eq!(stats.cancelled, 2);
+        assert_eq!(stats.jobs_done, 3);
+        assert_eq!(stats.lanes[0].dispatched, 5);
+    }
+
+    #[test]
+    fn full_lane_makes_room_by_dropping_cancelled_jobs() {
+        let mut config = config(1, 1, Duration::from_secs(10));
+        config.lanes.push(LaneConfig { name: "only", weight: 1, queue_capacity: 2 });
+        let pool = pool(config);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let held = occupy(&pool);
+        let first = pool.submit(counting(&counter)).unwrap();
+        pool.submit(counting(&counter)).unwrap();
+        assert_eq!(pool.submit(counting(&counter)).unwrap_err(), QueueError::Full);
+
+        first.cancel();
+        pool.submit(counting(&counter)).unwrap();
+        let stats = pool.stats();
+        assert_eq!((stats.queued, stats.cancelled, stats.lanes[0].rejected), (2, 1, 1));
+
+        drop(held);
+        assert!(pool.shutdown(Duration::from_secs(5)));
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+    }
+
+    /// Pool state with one lane per weight, holding `jobs[i]` jobs numbered by lane.
+    fn lanes(weights: &[u32], jobs: &[usize]) -> PoolState<usize> {
+        let lanes: Vec<_> = weights
+            .iter()
+            .zip(jobs)
+            .enumerate()
+            .map(|(index, (&weight, &count))| Lane {
+                config: LaneConfig { name: "lane", weight, queue_capacity: usize::MAX },
+                queue: (0..count).map(|_| Queued { job: index, cancel: CancelHandle::default() }).collect(),
+                credit: 0,
+                dispatched: 0,
+                rejected: 0,
+            })
+            .collect();
+        PoolState {
+            lanes,
+            queued: jobs.iter().sum(),
+            workers: BTreeMap::new(),
+            idle: 0,
+            next_worker_id: 0,
+            handles: HashMap::new(),
+            jobs_done: 0,
+            cancelled: 0,
+            shutting_down: false,
+        }
+    }
+
+    fn take(state: &mut PoolState<usize>, n: usize) -> Vec<usize> {
+        (0..n).map_while(|_| state.next_job().map(|queued| queued.job)).collect()
+    }
+
+    #[test]
+    fn busy_lanes_split_by_weight() {
+        let mut state = lanes(&[8, 3, 1], &[1000, 1000, 1000]);
+        let picked = take(&mut state, 120);
+        let share = |lane| picked.iter().filter(|&&l| l == lane).count();
+        assert_eq!((share(0), share(1), share(2)), (80, 30, 10));
+        assert_eq!(state.lanes.iter().map(|l| l.dispatched).collect::<Vec<_>>(), [80, 30, 10]);
+        assert_eq!(state.queued, 3000 - 120);
+
+        // Interleaved: every round of 12 already has each lane's share
+        for round in picked.chunks(12) {
+            let count = |lane| round.iter().filter(|&&l| l == lane).count();
+            assert_eq!((count(0), count(1), count(2)), (8, 3, 1));
+        }
+    }
+
+    #[test]
+    fn empty_lanes_leave_their_share_to_the_others() {
+        let mut state = lanes(&[8, 3, 1], &[0, 40, 40]);
+        let picked = take(&mut state, 40);
+        assert_eq!(picked.iter().filter(|&&l| l == 1).count(), 30);
+
+        // Once a lane runs dry the rest drain in order
+        let mut state = lanes(&[8, 3, 1], &[2, 0, 3]);
+        assert_eq!(take(&mut state, 10), [0, 0, 2, 2, 2]);
+        assert_eq!(state.queued, 0);
+        assert!(state.next_job().is_none());
+    }
+
+    #[test]
+    fn low_weight_lane_is_served_within_a_round() {
+        let mut state = lanes(&[8, 3, 1], &[1000, 1000, 1]);
+        assert!(take(&mut state, 12).contains(&2));
+
+        // Equal weights alternate, ties going to the earlier lane
+        let mut state = lanes(&[1, 1], &[3, 3]);
+        assert_eq!(take(&mut state, 6), [0, 1, 0, 1, 0, 1]);
+    }
+}
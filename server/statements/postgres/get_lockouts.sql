@@ -0,0 +1 @@
+SELECT lockKey, lockedUntil FROM "LOGIN_LOCKOUT" WHERE lockedUntil > now()
@@ -0,0 +1 @@
+SELECT id, passwordHash, banned FROM "USER" WHERE userName = $1
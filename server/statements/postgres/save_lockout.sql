@@ -0,0 +1 @@
+INSERT INTO "LOGIN_LOCKOUT" (lockKey, lockedUntil) VALUES ($1, to_timestamp($2::bigint)) ON CONFLICT (lockKey) DO UPDATE SET lockedUntil = EXCLUDED.lockedUntil
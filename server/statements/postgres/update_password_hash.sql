@@ -0,0 +1 @@
+UPDATE "USER" SET passwordHash = $2 WHERE id = $1::int
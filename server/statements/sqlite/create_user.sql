@@ -0,0 +1,2 @@
+-- No row back means the name was already taken
+INSERT INTO "USER" (userName, passwordHash) VALUES (?1, ?2) ON CONFLICT (userName) DO NOTHING RETURNING id
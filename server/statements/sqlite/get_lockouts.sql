@@ -0,0 +1,2 @@
+-- lockedUntil is stored as unix seconds
+SELECT lockKey, lockedUntil FROM "LOGIN_LOCKOUT" WHERE lockedUntil > CAST(strftime('%s', 'now') AS INTEGER)
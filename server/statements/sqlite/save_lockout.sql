@@ -0,0 +1 @@
+INSERT INTO "LOGIN_LOCKOUT" (lockKey, lockedUntil) VALUES (?1, CAST(?2 AS INTEGER)) ON CONFLICT (lockKey) DO UPDATE SET lockedUntil = excluded.lockedUntil
@@ -0,0 +1 @@
+UPDATE "USER" SET passwordHash = ?2 WHERE id = CAST(?1 AS INTEGER)